@@ -0,0 +1,263 @@
+extern crate rusttype;
+
+use std::sync::Arc;
+use canvas::Canvas;
+use self::rusttype::Font;
+
+/// Canvas that encodes commands as a compact stream decoded by `script.js`.
+///
+/// Every command is a single opcode byte followed by its arguments: numbers as little-endian
+/// 32-bit floats, booleans as single bytes and strings as a LEB128 length followed by UTF-8 bytes.
+/// Opcodes follow the order of the `Canvas` methods.
+pub struct BinaryCanvas<'a> {
+    bytes: Vec<u8>,
+    font: Arc<Font<'a>>,
+}
+
+const TRANSLATE: u8 = 0;
+const FILL_TEXT: u8 = 1;
+const FILL_RECT: u8 = 2;
+const RECT: u8 = 3;
+const ARC: u8 = 4;
+const ELLIPSE: u8 = 5;
+const MOVE_TO: u8 = 6;
+const LINE_TO: u8 = 7;
+const SET_LINE_DASH: u8 = 8;
+const ROTATE: u8 = 9;
+const SCALE: u8 = 10;
+const FILL_STYLE: u8 = 11;
+const TEXT_ALIGN: u8 = 12;
+const TEXT_BASELINE: u8 = 13;
+const LINE_WIDTH: u8 = 14;
+const STROKE_STYLE: u8 = 15;
+const FONT: u8 = 16;
+const SAVE: u8 = 17;
+const RESTORE: u8 = 18;
+const BEGIN_PATH: u8 = 19;
+const CLOSE_PATH: u8 = 20;
+const FILL: u8 = 21;
+const STROKE: u8 = 22;
+const CLIP: u8 = 23;
+
+/// Appends `n` in LEB128 - seven bits per byte, lowest first.
+pub fn write_length(bytes: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        bytes.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    bytes.push(n as u8);
+}
+
+impl<'a> BinaryCanvas<'a> {
+    pub fn new(font: Arc<Font<'a>>) -> BinaryCanvas<'a> {
+        BinaryCanvas {
+            bytes: Vec::new(),
+            font: font,
+        }
+    }
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+    fn op(&mut self, opcode: u8, numbers: &[f64]) -> &mut BinaryCanvas<'a> {
+        self.bytes.push(opcode);
+        self.op_args(numbers)
+    }
+    fn op_args(&mut self, numbers: &[f64]) -> &mut BinaryCanvas<'a> {
+        for &number in numbers {
+            let bits = (number as f32).to_bits();
+            for i in 0..4 {
+                self.bytes.push((bits >> (i * 8)) as u8);
+            }
+        }
+        self
+    }
+    fn string(&mut self, opcode: u8, s: &str) -> &mut BinaryCanvas<'a> {
+        self.bytes.push(opcode);
+        write_length(&mut self.bytes, s.len());
+        self.bytes.extend_from_slice(s.as_bytes());
+        self
+    }
+}
+
+impl<'a> Canvas for BinaryCanvas<'a> {
+    fn get_font_metrics(&self, scale: f64) -> rusttype::VMetrics {
+        self.font.v_metrics(rusttype::Scale {
+            x: scale as f32,
+            y: scale as f32,
+        })
+    }
+    fn translate(&mut self, x: f64, y: f64) -> &mut Canvas {
+        self.op(TRANSLATE, &[x, y])
+    }
+    fn fillText(&mut self, text: &str, x: f64, y: f64) -> &mut Canvas {
+        self.string(FILL_TEXT, text).op_args(&[x, y])
+    }
+    fn fillRect(&mut self, x: f64, y: f64, w: f64, h: f64) -> &mut Canvas {
+        self.op(FILL_RECT, &[x, y, w, h])
+    }
+    fn rect(&mut self, x: f64, y: f64, w: f64, h: f64) -> &mut Canvas {
+        self.op(RECT, &[x, y, w, h])
+    }
+    fn arc(
+        &mut self,
+        x: f64,
+        y: f64,
+        r: f64,
+        alpha: f64,
+        beta: f64,
+        clockwise: bool,
+    ) -> &mut Canvas {
+        self.op(ARC, &[x, y, r, alpha, beta]);
+        self.bytes.push(clockwise as u8);
+        self
+    }
+    fn ellipse(
+        &mut self,
+        x: f64,
+        y: f64,
+        rx: f64,
+        ry: f64,
+        rotation: f64,
+        alpha: f64,
+        beta: f64,
+        anticlockwise: bool,
+    ) -> &mut Canvas {
+        self.op(ELLIPSE, &[x, y, rx, ry, rotation, alpha, beta]);
+        self.bytes.push(anticlockwise as u8);
+        self
+    }
+    fn moveTo(&mut self, x: f64, y: f64) -> &mut Canvas {
+        self.op(MOVE_TO, &[x, y])
+    }
+    fn lineTo(&mut self, x: f64, y: f64) -> &mut Canvas {
+        self.op(LINE_TO, &[x, y])
+    }
+    fn setLineDash(&mut self, dash: &Vec<f64>) -> &mut Canvas {
+        self.bytes.push(SET_LINE_DASH);
+        write_length(&mut self.bytes, dash.len());
+        self.op_args(dash)
+    }
+    fn rotate(&mut self, alpha: f64) -> &mut Canvas {
+        self.op(ROTATE, &[alpha])
+    }
+    fn scale(&mut self, scale: f64) -> &mut Canvas {
+        self.op(SCALE, &[scale])
+    }
+    fn fillStyle(&mut self, style: &str) -> &mut Canvas {
+        self.string(FILL_STYLE, style)
+    }
+    fn textAlign(&mut self, align: &str) -> &mut Canvas {
+        self.string(TEXT_ALIGN, align)
+    }
+    fn textBaseline(&mut self, baseline: &str) -> &mut Canvas {
+        self.string(TEXT_BASELINE, baseline)
+    }
+    fn lineWidth(&mut self, width: f64) -> &mut Canvas {
+        self.op(LINE_WIDTH, &[width])
+    }
+    fn strokeStyle(&mut self, style: &str) -> &mut Canvas {
+        self.string(STROKE_STYLE, style)
+    }
+    fn font(&mut self, font: &str) -> &mut Canvas {
+        self.string(FONT, font)
+    }
+    fn save(&mut self) -> &mut Canvas {
+        self.op(SAVE, &[])
+    }
+    fn restore(&mut self) -> &mut Canvas {
+        self.op(RESTORE, &[])
+    }
+    fn beginPath(&mut self) -> &mut Canvas {
+        self.op(BEGIN_PATH, &[])
+    }
+    fn closePath(&mut self) -> &mut Canvas {
+        self.op(CLOSE_PATH, &[])
+    }
+    fn fill(&mut self) -> &mut Canvas {
+        self.op(FILL, &[])
+    }
+    fn stroke(&mut self) -> &mut Canvas {
+        self.op(STROKE, &[])
+    }
+    fn clip(&mut self) -> &mut Canvas {
+        self.op(CLIP, &[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use json_canvas::JsonCanvas;
+    use vm::Vm;
+    use text_type;
+    use Frame;
+    use Visible;
+    use WorldPoint;
+
+    fn font() -> Arc<Font<'static>> {
+        let collection = rusttype::FontCollection::from_bytes(&include_bytes!(
+            "html/fonts/iosevka-regular.ttf"
+        )[..]);
+        Arc::new(collection.into_font().unwrap())
+    }
+
+    #[test]
+    fn encodes_commands() {
+        let mut c = BinaryCanvas::new(font());
+        c.translate(1., -2.).fillStyle("#000").save();
+        let mut expected = vec![TRANSLATE, 0, 0, 0x80, 0x3f, 0, 0, 0, 0xc0];
+        expected.extend_from_slice(&[FILL_STYLE, 4, b'#', b'0', b'0', b'0']);
+        expected.push(SAVE);
+        assert_eq!(c.into_bytes(), expected);
+        let mut bytes = Vec::new();
+        write_length(&mut bytes, 300);
+        assert_eq!(bytes, vec![0xac, 0x02]);
+    }
+
+    /// Compares the size and encoding time of a blueprint with a few hundred frames.
+    ///
+    /// Run with `cargo test --release canvas_benchmark -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn canvas_benchmark() {
+        const ROUNDS: u32 = 50;
+        let vm = Vm::new_headless();
+        let blueprint = vm.borrow_mut().add_blueprint("Benchmark".to_string());
+        let frames: Vec<_> = (0..300)
+            .map(|i| {
+                let frame = Frame::new(Arc::new(text_type), &blueprint, true);
+                let (column, row) = ((i % 20) as f64, (i / 20) as f64);
+                frame.borrow_mut().pos = WorldPoint::new(column * 40., row * 30.);
+                frame
+            })
+            .collect();
+        let font = font();
+
+        let start = Instant::now();
+        let mut json = Vec::new();
+        for _ in 0..ROUNDS {
+            let mut c = JsonCanvas::new(font.clone());
+            for frame in frames.iter() {
+                frame.draw(&mut c);
+            }
+            json = c.serialize().into_bytes();
+        }
+        let json_time = start.elapsed() / ROUNDS;
+
+        let start = Instant::now();
+        let mut binary = Vec::new();
+        for _ in 0..ROUNDS {
+            let mut c = BinaryCanvas::new(font.clone());
+            for frame in frames.iter() {
+                frame.draw(&mut c);
+            }
+            binary = c.into_bytes();
+        }
+        let binary_time = start.elapsed() / ROUNDS;
+
+        println!("JsonCanvas:   {} bytes, {:?} per rendering", json.len(), json_time);
+        println!("BinaryCanvas: {} bytes, {:?} per rendering", binary.len(), binary_time);
+        assert!(binary.len() < json.len());
+    }
+}
@@ -1,5 +1,6 @@
 use std::sync::{Arc, Weak};
 use std::cell::RefCell;
+use std::collections::HashMap;
 extern crate serde;
 extern crate serde_json;
 
@@ -8,17 +9,29 @@ use Frame;
 use Link;
 use machine::Machine;
 use Object;
+use LinkTerminator;
+use FrameParam;
 use WorldPoint;
 use WorldSize;
 use SerializableVec;
+use load::*;
+use id::*;
+use history::*;
+use nested::blueprint_type;
 
 pub struct Blueprint {
+    pub id: Id,
     pub vm: Weak<RefCell<Vm>>,
     pub name: String,
     pub frames: Vec<Arc<RefCell<Frame>>>,
     pub links: Vec<Arc<RefCell<Link>>>,
     pub machines: Vec<Arc<RefCell<Machine>>>,
     pub active_machine: Weak<RefCell<Machine>>,
+    /// Frame started when a frame instantiating this blueprint is run.
+    pub entry: Weak<RefCell<Frame>>,
+    pub frames_by_id: HashMap<Id, Arc<RefCell<Frame>>>,
+    pub machines_by_id: HashMap<Id, Arc<RefCell<Machine>>>,
+    pub history: History,
 }
 
 use self::serde::ser::{Serialize, Serializer, SerializeSeq, SerializeStruct};
@@ -28,7 +41,8 @@ impl Serialize for Blueprint {
     where
         S: Serializer,
     {
-        let mut serializer = serializer.serialize_struct("Blueprint", 4)?;
+        let mut serializer = serializer.serialize_struct("Blueprint", 7)?;
+        serializer.serialize_field("id", &self.id)?;
         serializer.serialize_field("name", &self.name)?;
         serializer.serialize_field(
             "frames",
@@ -44,9 +58,11 @@ impl Serialize for Blueprint {
         )?;
         serializer.serialize_field(
             "active_machine",
-            &self.machine_index(
-                &self.active_machine.upgrade().unwrap(),
-            ),
+            &self.active_machine.upgrade().unwrap().borrow().id,
+        )?;
+        serializer.serialize_field(
+            "entry",
+            &self.entry_frame().map(|frame| frame.borrow().id),
         )?;
         serializer.end()
         /*
@@ -64,94 +80,130 @@ impl Serialize for Blueprint {
 
 impl Blueprint {
     pub fn new(vm: &Arc<RefCell<Vm>>) -> Arc<RefCell<Blueprint>> {
-        let bp = Arc::new(RefCell::new(Blueprint {
-            vm: Arc::downgrade(vm),
+        Blueprint::with_id(new_id(), vm)
+    }
+
+    pub fn with_id(id: Id, vm: &Arc<RefCell<Vm>>) -> Arc<RefCell<Blueprint>> {
+        let bp = Blueprint::detached(id, Arc::downgrade(vm));
+        vm.borrow_mut().blueprints.push(bp.clone());
+        return bp;
+    }
+
+    /// Creates a blueprint without adding it to the VM - for use while the VM is borrowed.
+    pub fn detached(id: Id, vm: Weak<RefCell<Vm>>) -> Arc<RefCell<Blueprint>> {
+        reserve_id(id);
+        Arc::new(RefCell::new(Blueprint {
+            id: id,
+            vm: vm,
             name: String::new(),
             frames: Vec::new(),
             links: Vec::new(),
             machines: Vec::new(),
             active_machine: Weak::new(),
-        }));
-        vm.borrow_mut().blueprints.push(bp.clone());
-        return bp;
+            entry: Weak::new(),
+            frames_by_id: HashMap::new(),
+            machines_by_id: HashMap::new(),
+            history: History::new(),
+        }))
     }
 
-    pub fn load_json(this: &Arc<RefCell<Blueprint>>, json: &serde_json::Value) {
-        let blueprint_rc = this;
-        let frames = json.get("frames").unwrap().as_array().unwrap();
-        for frame_json in frames.iter() {
-            let type_name = frame_json.get("type").unwrap().as_str().unwrap();
-            let global = frame_json.get("global").unwrap().as_bool().unwrap();
-            let vm_rc = blueprint_rc.borrow().vm.upgrade().unwrap();
-            let vm = vm_rc.borrow();
-            let typ = vm.types.iter().find(|typ| typ.name == type_name).unwrap();
-            let frame = Frame::new(typ, &blueprint_rc, global);
-            let pos_array = frame_json.get("pos").unwrap().as_array().unwrap();
-            frame.borrow_mut().pos = WorldPoint::new(
-                pos_array[0].as_f64().unwrap(),
-                pos_array[1].as_f64().unwrap(),
-            );
-            let size_array = frame_json.get("size").unwrap().as_array().unwrap();
-            frame.borrow_mut().size = WorldSize::new(
-                size_array[0].as_f64().unwrap(),
-                size_array[1].as_f64().unwrap(),
-            );
+    /// Loads the frames of a blueprint. Must be done for all blueprints before `load_json` because
+    /// objects of frames that instantiate other blueprints refer to their frames.
+    pub fn load_frames_json(
+        this: &Arc<RefCell<Blueprint>>,
+        json: &serde_json::Value,
+    ) -> LoadResult<()> {
+        let frames = get_array(json, "frames")?;
+        for (i, frame_json) in frames.iter().enumerate() {
+            Blueprint::load_frame_json(this, frame_json).at(Location::Frame(i))?;
         }
+        Ok(())
+    }
 
-        let name = json.get("name").unwrap().as_str().unwrap();
+    pub fn load_json(this: &Arc<RefCell<Blueprint>>, json: &serde_json::Value) -> LoadResult<()> {
+        let blueprint_rc = this;
+        let name = get_str(json, "name")?;
         blueprint_rc.borrow_mut().name = String::from(name);
 
-        let links = json.get("links").unwrap().as_array().unwrap();
-        for link_json in links.iter() {
-
-            use LinkTerminator;
-            fn parse_terminator(
-                blueprint: &Blueprint,
-                link_json: &serde_json::Value,
-                side: &str,
-            ) -> LinkTerminator {
-                let a = link_json.get(side).unwrap().as_object().unwrap();
-                let terminator_type = a.keys().next().unwrap();
-                use FrameParam;
-                match terminator_type.as_ref() {
-                    "Frame" => {
-                        let frame_idx = a.get("Frame").unwrap().as_array().unwrap()[0]
-                            .as_u64()
-                            .unwrap();
-                        LinkTerminator::Frame(blueprint.frames[frame_idx as usize].clone())
-                    }
-                    "FrameParam" => {
-                        let ref frame_param = a.get("FrameParam").unwrap().as_array().unwrap()[0];
-                        let frame_idx = frame_param.get("frame").unwrap().as_u64().unwrap().clone();
-                        let param_index =
-                            frame_param.get("param_index").unwrap().as_u64().unwrap() as usize;
-                        LinkTerminator::FrameParam(FrameParam {
-                            frame: blueprint.frames[frame_idx as usize].clone(),
-                            param_index: param_index,
-                        })
-                    }
-                    _ => panic!("Unknown LinkTerminator type"),
-                }
-            }
+        let links = get_array(json, "links")?;
+        for (i, link_json) in links.iter().enumerate() {
             use std::ops::Deref;
             let mut bp = blueprint_rc.borrow_mut();
-            let order = link_json.get("order").unwrap().as_i64().unwrap();
-            let link = Link {
-                blueprint: Arc::downgrade(blueprint_rc),
-                a: parse_terminator(bp.deref(), &link_json, "a"),
-                b: parse_terminator(bp.deref(), &link_json, "b"),
-                order: order as i32,
+            let id = get_id(link_json, "id").at(Location::Link(i))?;
+            if bp.links.iter().any(|link| link.borrow().id == id) {
+                return Err(duplicate_id(id).at(Location::Field("id")).at(Location::Link(i)));
+            }
+            let link = {
+                let parse = |side: &'static str| {
+                    parse_terminator(bp.deref(), link_json, side).at(Location::Field(side))
+                };
+                Link {
+                    id: id,
+                    blueprint: Arc::downgrade(blueprint_rc),
+                    a: parse("a").at(Location::Link(i))?,
+                    b: parse("b").at(Location::Link(i))?,
+                    order: get_i64(link_json, "order").at(Location::Link(i))? as i32,
+                }
             };
+            reserve_id(link.id);
             bp.links.push(Arc::new(RefCell::new(link)));
         }
-        let machines = json.get("machines").unwrap().as_array().unwrap();
-        for machine_json in machines.iter() {
-            let machine = Machine::new(&blueprint_rc);
-            Machine::load_json(&machine, machine_json);
+        let machines = get_array(json, "machines")?;
+        for (i, machine_json) in machines.iter().enumerate() {
+            let id = get_id(machine_json, "id").at(Location::Machine(i))?;
+            if blueprint_rc.borrow().machine(id).is_some() {
+                return Err(duplicate_id(id).at(Location::Field("id")).at(Location::Machine(i)));
+            }
+            let machine = Machine::with_id(id, &blueprint_rc);
+            Machine::load_json(&machine, machine_json).at(Location::Machine(i))?;
         }
-        let active_machine = json.get("active_machine").unwrap().as_i64().unwrap();
-        let weak = Arc::downgrade(&blueprint_rc.borrow().machines[active_machine as usize]);
-        blueprint_rc.borrow_mut().active_machine = weak;
+        let active_machine = get_id(json, "active_machine")?;
+        let active_machine = blueprint_rc
+            .borrow()
+            .machine(active_machine)
+            .ok_or_else(|| {
+                LoadError::new(LoadErrorKind::UnknownId(active_machine))
+                    .at(Location::Field("active_machine"))
+            })?;
+        blueprint_rc.borrow_mut().active_machine = Arc::downgrade(&active_machine);
+        let entry = field(json, "entry")?;
+        if !entry.is_null() {
+            let entry = as_id(entry)
+                .and_then(|id| find_frame(&blueprint_rc.borrow(), id))
+                .at(Location::Field("entry"))?;
+            blueprint_rc.borrow_mut().entry = Arc::downgrade(&entry);
+        }
+        Ok(())
+    }
+
+    fn load_frame_json(
+        blueprint_rc: &Arc<RefCell<Blueprint>>,
+        frame_json: &serde_json::Value,
+    ) -> LoadResult<()> {
+        let id = get_id(frame_json, "id")?;
+        if blueprint_rc.borrow().frame(id).is_some() {
+            return Err(duplicate_id(id).at(Location::Field("id")));
+        }
+        let type_name = get_str(frame_json, "type")?;
+        let global = get_bool(frame_json, "global")?;
+        let (x, y) = get_pair(frame_json, "pos")?;
+        let (width, height) = get_pair(frame_json, "size")?;
+        let vm_rc = blueprint_rc.borrow().vm.upgrade().unwrap();
+        let vm = vm_rc.borrow();
+        let frame = if type_name == blueprint_type.name {
+            let child = get_id(frame_json, "blueprint").and_then(|id| {
+                vm.blueprint(id).ok_or_else(|| {
+                    LoadError::new(LoadErrorKind::UnknownId(id)).at(Location::Field("blueprint"))
+                })
+            })?;
+            Frame::instance(&vm.types, id, &child, &blueprint_rc, global)
+        } else {
+            let typ = vm.types.find(type_name).at(Location::Field("type"))?;
+            Frame::with_id(id, typ, &blueprint_rc, global)
+        };
+        frame.borrow_mut().pos = WorldPoint::new(x, y);
+        frame.borrow_mut().size = WorldSize::new(width, height);
+        Ok(())
     }
 
     pub fn rename(&mut self, name: String) {
@@ -189,21 +241,160 @@ impl Blueprint {
             .cloned()
     }
 
-    pub fn frame_index(&self, frame: &Arc<RefCell<Frame>>) -> u32 {
-        for (i, other) in self.frames.iter().enumerate() {
-            if Arc::ptr_eq(frame, other) {
-                return i as u32;
+    pub fn frame_snapshot(&self, frame: &Arc<RefCell<Frame>>) -> Option<FrameSnapshot> {
+        let index = match self.frames.iter().position(|other| Arc::ptr_eq(other, frame)) {
+            Some(index) => index,
+            None => return None,
+        };
+        let links = self.links
+            .iter()
+            .enumerate()
+            .filter(|&(_, link)| link.borrow().touches(frame))
+            .map(|(i, link)| (i, link.clone()))
+            .collect();
+        let mut objects = Vec::new();
+        for machine_rc in self.machines.iter() {
+            let machine = machine_rc.borrow();
+            for (i, object) in machine.objects.iter().enumerate() {
+                if Arc::ptr_eq(&object.borrow().frame, frame) {
+                    objects.push((machine_rc.clone(), i, object.clone()));
+                }
+            }
+        }
+        Some(FrameSnapshot {
+            frame: frame.clone(),
+            index: index,
+            links: links,
+            objects: objects,
+        })
+    }
+
+    /// Removes `frame` together with its objects and all links attached to it.
+    pub fn remove_frame(&mut self, frame: &Arc<RefCell<Frame>>) -> Option<FrameSnapshot> {
+        let snapshot = self.frame_snapshot(frame);
+        if let Some(ref snapshot) = snapshot {
+            self.frames.remove(snapshot.index);
+            self.frames_by_id.remove(&frame.borrow().id);
+            self.links.retain(|link| !link.borrow().touches(frame));
+            for machine in self.machines.iter() {
+                machine.borrow_mut().objects.retain(|o_rc| {
+                    !Arc::ptr_eq(&o_rc.borrow().frame, frame)
+                });
             }
         }
-        panic!("Bad frame reference");
+        snapshot
     }
 
-    pub fn machine_index(&self, machine: &Arc<RefCell<Machine>>) -> u32 {
-        for (i, other) in self.machines.iter().enumerate() {
-            if Arc::ptr_eq(machine, other) {
-                return i as u32;
+    /// Reverses `remove_frame`.
+    pub fn insert_frame(&mut self, snapshot: &FrameSnapshot) {
+        let index = snapshot.index.min(self.frames.len());
+        self.frames.insert(index, snapshot.frame.clone());
+        self.frames_by_id.insert(
+            snapshot.frame.borrow().id,
+            snapshot.frame.clone(),
+        );
+        for &(i, ref link) in snapshot.links.iter() {
+            let i = i.min(self.links.len());
+            self.links.insert(i, link.clone());
+        }
+        for &(ref machine, i, ref object) in snapshot.objects.iter() {
+            let mut machine = machine.borrow_mut();
+            let i = i.min(machine.objects.len());
+            machine.objects.insert(i, object.clone());
+        }
+        // Machines added since the frame was removed start with a fresh object.
+        let frame = &snapshot.frame;
+        for (i, machine) in self.machines.iter().enumerate() {
+            if (i == 0 || !frame.borrow().global) && machine.borrow().find_object(frame).is_none() {
+                Machine::init_object(machine, frame);
             }
         }
-        panic!("Bad machine reference");
     }
+
+    /// The entry frame, unless it was deleted.
+    pub fn entry_frame(&self) -> Option<Arc<RefCell<Frame>>> {
+        self.entry.upgrade().and_then(|entry| self.frame(entry.borrow().id))
+    }
+
+    pub fn frame(&self, id: Id) -> Option<Arc<RefCell<Frame>>> {
+        self.frames_by_id.get(&id).cloned()
+    }
+
+    pub fn machine(&self, id: Id) -> Option<Arc<RefCell<Machine>>> {
+        self.machines_by_id.get(&id).cloned()
+    }
+
+    /// Links that start at `param`, in the order of their arguments.
+    pub fn param_links(&self, param: &FrameParam) -> Vec<Arc<RefCell<Link>>> {
+        let mut links: Vec<_> = self.links
+            .iter()
+            .filter(|link| link.borrow().starts_at(param))
+            .cloned()
+            .collect();
+        // Stable, so that links with equal order keep the order in which they were created.
+        links.sort_by_key(|link| link.borrow().order);
+        links
+    }
+
+    /// `Link::order` for a new link that starts at `param` - after all existing ones.
+    pub fn next_order(&self, param: &FrameParam) -> i32 {
+        self.param_links(param).last().map_or(0, |link| link.borrow().order + 1)
+    }
+}
+
+fn parse_terminator(
+    blueprint: &Blueprint,
+    link_json: &serde_json::Value,
+    side: &'static str,
+) -> LoadResult<LinkTerminator> {
+    let terminator = field(link_json, side)?;
+    let terminator = terminator.as_object().ok_or_else(|| {
+        LoadError::new(LoadErrorKind::WrongType("an object"))
+    })?;
+    let (terminator_type, value) = terminator.iter().next().ok_or_else(|| {
+        LoadError::new(LoadErrorKind::WrongType("a link terminator"))
+    })?;
+    let value = value.as_array().and_then(|array| array.get(0)).ok_or_else(|| {
+        LoadError::new(LoadErrorKind::WrongType("a one-element array"))
+            .at(Location::Field("value"))
+    })?;
+    match terminator_type.as_ref() {
+        "Frame" => {
+            let frame = as_id(value).and_then(|id| find_frame(blueprint, id)).at(
+                Location::Field("Frame"),
+            )?;
+            Ok(LinkTerminator::Frame(frame))
+        }
+        "FrameParam" => {
+            let frame = get_id(value, "frame")
+                .and_then(|id| find_frame(blueprint, id))
+                .at(Location::Field("FrameParam"))?;
+            // Parameters of blueprint instances come and go with the parameter frames of the
+            // instantiated blueprint, so links may point past the current ones.
+            let param_count = if frame.borrow().child().is_some() {
+                usize::max_value()
+            } else {
+                frame.borrow().parameters().len()
+            };
+            let param_index = get_index(value, "param_index", param_count)
+                .at(Location::Field("FrameParam"))?;
+            Ok(LinkTerminator::FrameParam(FrameParam {
+                frame: frame,
+                param_index: param_index,
+            }))
+        }
+        "Point" => {
+            let (x, y) = as_pair(value).at(Location::Field("Point"))?;
+            Ok(LinkTerminator::Point(WorldPoint::new(x, y)))
+        }
+        other => Err(LoadError::new(
+            LoadErrorKind::UnknownTerminator(other.to_string()),
+        )),
+    }
+}
+
+pub fn find_frame(blueprint: &Blueprint, id: Id) -> LoadResult<Arc<RefCell<Frame>>> {
+    blueprint.frame(id).ok_or_else(|| {
+        LoadError::new(LoadErrorKind::UnknownId(id))
+    })
 }
@@ -0,0 +1,264 @@
+use std::sync::{Arc, Weak};
+use std::cell::RefCell;
+
+use blueprint::Blueprint;
+use canvas::Canvas;
+use menu::*;
+use machine_list::STATUS_WIDTH;
+use nested::PlaceBlueprintAction;
+use vm::Vm;
+use TouchReceiver;
+use WorldPoint;
+use DisplayPoint;
+use PARAM_RADIUS;
+
+const ROW_HEIGHT: f64 = PARAM_RADIUS * 3.;
+const WIDTH: f64 = 60.;
+
+/// Blueprint whose name is being typed in.
+pub struct Rename {
+    blueprint: Weak<RefCell<Blueprint>>,
+    original: String,
+}
+
+/// Top-left corner of the list - right of the machine circles and their statuses.
+fn origin(vm: &Vm) -> DisplayPoint {
+    let corner = vm.overlay_corner();
+    DisplayPoint::new(corner.x + PARAM_RADIUS * 2. + STATUS_WIDTH, corner.y - PARAM_RADIUS)
+}
+
+pub fn draw_blueprint_list(vm: &Vm, c: &mut Canvas) {
+    let origin = origin(vm);
+    let active = vm.active_blueprint.upgrade();
+    let renaming = vm.session().renaming.as_ref().and_then(|rename| rename.blueprint.upgrade());
+    c.textAlign("left");
+    c.textBaseline("middle");
+    for (i, blueprint) in vm.blueprints.iter().enumerate() {
+        let is_active = active.as_ref().map_or(false, |active| Arc::ptr_eq(active, blueprint));
+        c.fillStyle(if is_active { "#3e64a3" } else { "#959ba5" });
+        let mut text = blueprint.borrow().name.clone();
+        if renaming.as_ref().map_or(false, |renaming| Arc::ptr_eq(renaming, blueprint)) {
+            text.push('_');
+        }
+        let y = origin.y + ROW_HEIGHT * (i as f64 + 0.5);
+        c.fillText(text.as_ref(), origin.x, y);
+    }
+}
+
+pub fn blueprint_at(vm: &Vm, d: DisplayPoint) -> Option<Arc<RefCell<Blueprint>>> {
+    let origin = origin(vm);
+    if d.x < origin.x || d.x > origin.x + WIDTH || d.y < origin.y {
+        return None;
+    }
+    let row = ((d.y - origin.y) / ROW_HEIGHT) as usize;
+    vm.blueprints.get(row).cloned()
+}
+
+/// Menu shown over an entry of the blueprint list.
+pub fn blueprint_menu(blueprint: &Arc<RefCell<Blueprint>>) -> Menu {
+    Menu {
+        entries: vec![
+            Entry {
+                name: "Activate".to_string(),
+                color: None,
+                shortcuts: vec!["LMB".to_string()],
+                action: Box::new(ActivateBlueprintAction { blueprint: Arc::downgrade(blueprint) }),
+            },
+            Entry {
+                name: "Place".to_string(),
+                color: None,
+                shortcuts: vec!["MMB".to_string()],
+                action: Box::new(PlaceBlueprintAction { blueprint: Arc::downgrade(blueprint) }),
+            },
+            Entry {
+                name: "Rename".to_string(),
+                color: None,
+                shortcuts: vec!["F2".to_string()],
+                action: Box::new(RenameBlueprintAction { blueprint: Arc::downgrade(blueprint) }),
+            },
+            Entry {
+                name: "Delete".to_string(),
+                color: None,
+                shortcuts: vec!["Shift+Delete".to_string()],
+                action: Box::new(DeleteBlueprintAction { blueprint: Arc::downgrade(blueprint) }),
+            },
+            Entry {
+                name: "New blueprint".to_string(),
+                color: None,
+                shortcuts: vec!["Shift+Insert".to_string()],
+                action: Box::new(NewBlueprintAction),
+            },
+        ],
+        color: "#3e64a3".to_string(),
+    }
+}
+
+/// Keyboard shortcuts that work on the active blueprint.
+pub fn blueprint_entries(vm: &Vm) -> Vec<Entry> {
+    let active = vm.active_blueprint.clone();
+    vec![
+        Entry {
+            name: "New blueprint".to_string(),
+            color: None,
+            shortcuts: vec!["Shift+Insert".to_string()],
+            action: Box::new(NewBlueprintAction),
+        },
+        Entry {
+            name: "Rename blueprint".to_string(),
+            color: None,
+            shortcuts: vec!["F2".to_string()],
+            action: Box::new(RenameBlueprintAction { blueprint: active.clone() }),
+        },
+        Entry {
+            name: "Delete blueprint".to_string(),
+            color: None,
+            shortcuts: vec!["Shift+Delete".to_string()],
+            action: Box::new(DeleteBlueprintAction { blueprint: active }),
+        },
+        Entry {
+            name: "Next blueprint".to_string(),
+            color: None,
+            shortcuts: vec!["Shift+PageDown".to_string()],
+            action: Box::new(CycleBlueprintAction { forward: true }),
+        },
+        Entry {
+            name: "Previous blueprint".to_string(),
+            color: None,
+            shortcuts: vec!["Shift+PageUp".to_string()],
+            action: Box::new(CycleBlueprintAction { forward: false }),
+        },
+    ]
+}
+
+/// Feeds a key press to the blueprint being renamed. Enter confirms, Escape restores the old name.
+pub fn rename_key(vm: &mut Vm, code: &str, key: &str) {
+    let rename = match vm.session_mut().renaming.take() {
+        Some(rename) => rename,
+        None => return,
+    };
+    let blueprint = match rename.blueprint.upgrade() {
+        Some(blueprint) => blueprint,
+        None => return,
+    };
+    let mut blueprint = blueprint.borrow_mut();
+    match code {
+        "Enter" | "NumpadEnter" => return,
+        "Escape" => {
+            blueprint.rename(rename.original);
+            return;
+        }
+        "Backspace" => {
+            blueprint.name.pop();
+        }
+        _ if key.chars().count() == 1 => blueprint.name.push_str(key),
+        _ => (),
+    }
+    vm.session_mut().renaming = Some(rename);
+}
+
+struct NewBlueprintAction;
+
+impl Action for NewBlueprintAction {
+    fn start(
+        self: Box<Self>,
+        vm: &mut Vm,
+        _: DisplayPoint,
+        _: WorldPoint,
+    ) -> Option<Box<TouchReceiver>> {
+        let mut n = vm.blueprints.len() + 1;
+        let name = loop {
+            let name = format!("Blueprint {}", n);
+            if vm.blueprints.iter().all(|blueprint| blueprint.borrow().name != name) {
+                break name;
+            }
+            n += 1;
+        };
+        let blueprint = vm.add_blueprint(name);
+        vm.activate(&blueprint);
+        None
+    }
+}
+
+struct ActivateBlueprintAction {
+    blueprint: Weak<RefCell<Blueprint>>,
+}
+
+impl Action for ActivateBlueprintAction {
+    fn start(
+        self: Box<Self>,
+        vm: &mut Vm,
+        _: DisplayPoint,
+        _: WorldPoint,
+    ) -> Option<Box<TouchReceiver>> {
+        if let Some(blueprint) = self.blueprint.upgrade() {
+            vm.activate(&blueprint);
+        }
+        None
+    }
+}
+
+struct CycleBlueprintAction {
+    forward: bool,
+}
+
+impl Action for CycleBlueprintAction {
+    fn start(
+        self: Box<Self>,
+        vm: &mut Vm,
+        _: DisplayPoint,
+        _: WorldPoint,
+    ) -> Option<Box<TouchReceiver>> {
+        let active = vm.active_blueprint.upgrade().unwrap();
+        let count = vm.blueprints.len();
+        let idx = vm.blueprints
+            .iter()
+            .position(|blueprint| Arc::ptr_eq(blueprint, &active))
+            .unwrap();
+        let delta = if self.forward { 1 } else { count - 1 };
+        let next = vm.blueprints[(idx + delta) % count].clone();
+        vm.activate(&next);
+        None
+    }
+}
+
+struct RenameBlueprintAction {
+    blueprint: Weak<RefCell<Blueprint>>,
+}
+
+impl Action for RenameBlueprintAction {
+    fn start(
+        self: Box<Self>,
+        vm: &mut Vm,
+        _: DisplayPoint,
+        _: WorldPoint,
+    ) -> Option<Box<TouchReceiver>> {
+        if let Some(blueprint) = self.blueprint.upgrade() {
+            let original = blueprint.borrow().name.clone();
+            vm.session_mut().renaming = Some(Rename {
+                blueprint: self.blueprint,
+                original: original,
+            });
+        }
+        None
+    }
+}
+
+struct DeleteBlueprintAction {
+    blueprint: Weak<RefCell<Blueprint>>,
+}
+
+impl Action for DeleteBlueprintAction {
+    fn start(
+        self: Box<Self>,
+        vm: &mut Vm,
+        _: DisplayPoint,
+        _: WorldPoint,
+    ) -> Option<Box<TouchReceiver>> {
+        if let Some(blueprint) = self.blueprint.upgrade() {
+            if let Err(err) = vm.remove_blueprint(&blueprint) {
+                println!("{}", err);
+            }
+        }
+        None
+    }
+}
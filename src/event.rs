@@ -8,6 +8,8 @@ use std::cell::RefCell;
 use ObjectCell;
 use std::collections::linked_list;
 use std::any::Any;
+use std::sync::Arc;
+use types::FrameType;
 
 type Closure = Box<FnMut() + Send>;
 
@@ -16,67 +18,90 @@ pub enum Event {
     NewWebsocketClient(websocket::Client<TcpStream>),
     Closure(Closure),
     RunUpdate(u64, Box<Any + Send>),
+    /// Makes a new frame type available - plugins can send this from any thread.
+    RegisterType(Arc<FrameType>),
     WebsocketDisconnected(i64),
+    /// Input from the websocket client with the given id.
+    Client(i64, ClientEvent),
+}
+
+/// Events sent by a browser over its websocket.
+pub enum ClientEvent {
+    /// Chooses the encoding of renderings - see `rendering::Protocol`.
+    Protocol { binary: bool },
     RenderingReady, // sent when next frame is ready for commands
     RenderingDone, // sent after all rendering commands are flushed
-    DisplaySize { width: f64, height: f64 },
+    DisplaySize { width: f64, height: f64, dpi: f64 },
     MouseMove { x: f64, y: f64 },
     MouseWheel { x: f64, y: f64 },
     MouseDown { x: f64, y: f64, button: i64 },
     MouseUp { x: f64, y: f64, button: i64 },
-    KeyDown { code: String, key: String },
+    KeyDown {
+        code: String,
+        key: String,
+        ctrl: bool,
+        shift: bool,
+    },
     KeyUp { code: String, key: String },
 }
 
-impl Event {
-    pub fn from(json: serde_json::Value) -> Option<Event> {
+impl ClientEvent {
+    pub fn from(json: serde_json::Value) -> Option<ClientEvent> {
         let obj = json.as_object().unwrap();
         let typ = obj.get("type").unwrap().as_str().unwrap();
 
 
         match typ.as_ref() {
             "size" => {
-                Some(Event::DisplaySize {
+                Some(ClientEvent::DisplaySize {
                     width: obj.get("width").unwrap().as_f64().unwrap(),
                     height: obj.get("height").unwrap().as_f64().unwrap(),
+                    dpi: obj.get("dpi").and_then(|x| x.as_f64()).unwrap_or(96.),
                 })
             }
             "mouse_move" => {
-                Some(Event::MouseMove {
+                Some(ClientEvent::MouseMove {
                     x: obj.get("x").unwrap().as_f64().unwrap(),
                     y: obj.get("y").unwrap().as_f64().unwrap(),
                 })
             }
             "mouse_down" => {
-                Some(Event::MouseDown {
+                Some(ClientEvent::MouseDown {
                     x: obj.get("x").unwrap().as_f64().unwrap(),
                     y: obj.get("y").unwrap().as_f64().unwrap(),
                     button: obj.get("button").unwrap().as_i64().unwrap(),
                 })
             }
             "mouse_up" => {
-                Some(Event::MouseUp {
+                Some(ClientEvent::MouseUp {
                     x: obj.get("x").unwrap().as_f64().unwrap(),
                     y: obj.get("y").unwrap().as_f64().unwrap(),
                     button: obj.get("button").unwrap().as_i64().unwrap(),
                 })
             }
-            "render_done" => Some(Event::RenderingDone),
-            "render_ready" => Some(Event::RenderingReady),
+            "protocol" => {
+                Some(ClientEvent::Protocol {
+                    binary: obj.get("binary").and_then(|x| x.as_bool()).unwrap_or(false),
+                })
+            }
+            "render_done" => Some(ClientEvent::RenderingDone),
+            "render_ready" => Some(ClientEvent::RenderingReady),
             "key_up" => {
-                Some(Event::KeyUp {
+                Some(ClientEvent::KeyUp {
                     key: String::from(obj.get("key").unwrap().as_str().unwrap()),
                     code: String::from(obj.get("code").unwrap().as_str().unwrap()),
                 })
             }
             "key_down" => {
-                Some(Event::KeyDown {
+                Some(ClientEvent::KeyDown {
                     key: String::from(obj.get("key").unwrap().as_str().unwrap()),
                     code: String::from(obj.get("code").unwrap().as_str().unwrap()),
+                    ctrl: obj.get("ctrl").and_then(|x| x.as_bool()).unwrap_or(false),
+                    shift: obj.get("shift").and_then(|x| x.as_bool()).unwrap_or(false),
                 })
             }
             "wheel" => {
-                Some(Event::MouseWheel {
+                Some(ClientEvent::MouseWheel {
                     x: obj.get("x").unwrap().as_f64().unwrap(),
                     y: obj.get("y").unwrap().as_f64().unwrap(),
                 })
@@ -0,0 +1,64 @@
+//! Blueprints built by the tests of several modules.
+
+use std::sync::Arc;
+use std::cell::RefCell;
+
+use blueprint::Blueprint;
+use id::new_id;
+use vm::Vm;
+use Frame;
+use FrameParam;
+use Link;
+use LinkTerminator;
+
+/// Headless VM whose active blueprint, `name`, has a single machine.
+pub fn blueprint(name: &str) -> (Arc<RefCell<Vm>>, Arc<RefCell<Blueprint>>) {
+    let vm = Vm::new_headless();
+    let blueprint = vm.borrow_mut().add_blueprint(name.to_string());
+    vm.borrow_mut().activate(&blueprint);
+    (vm, blueprint)
+}
+
+/// Adds a frame of the type registered as `type_name`.
+pub fn frame(
+    blueprint: &Arc<RefCell<Blueprint>>,
+    type_name: &str,
+    global: bool,
+) -> Arc<RefCell<Frame>> {
+    let vm = blueprint.borrow().vm.upgrade().unwrap();
+    let typ = vm.borrow().types.get(type_name).unwrap();
+    Frame::new(typ, blueprint, global)
+}
+
+/// Adds a global Text frame holding `contents`.
+pub fn text(blueprint: &Arc<RefCell<Blueprint>>, contents: &str) -> Arc<RefCell<Frame>> {
+    let frame = frame(blueprint, "Text", true);
+    let object = blueprint.borrow().get_object(&frame);
+    object.borrow_mut().data = Box::new(contents.to_string());
+    frame
+}
+
+pub fn param(frame: &Arc<RefCell<Frame>>, i: usize) -> LinkTerminator {
+    LinkTerminator::FrameParam(FrameParam {
+        frame: frame.clone(),
+        param_index: i,
+    })
+}
+
+/// Adds a link from `a` to `b`.
+pub fn link(
+    blueprint: &Arc<RefCell<Blueprint>>,
+    a: LinkTerminator,
+    b: LinkTerminator,
+    order: i32,
+) -> Arc<RefCell<Link>> {
+    let link = Arc::new(RefCell::new(Link {
+        id: new_id(),
+        blueprint: Arc::downgrade(blueprint),
+        a: a,
+        b: b,
+        order: order,
+    }));
+    blueprint.borrow_mut().links.push(link.clone());
+    link
+}
@@ -0,0 +1,316 @@
+use std::sync::Arc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use blueprint::Blueprint;
+use machine::Machine;
+use menu::*;
+use vm::Vm;
+use Frame;
+use Link;
+use ObjectCell;
+use TouchReceiver;
+use WorldPoint;
+use WorldSize;
+use DisplayPoint;
+
+/// Everything that disappears from a blueprint together with a frame.
+///
+/// Positions are remembered so that undoing a deletion puts the frame, its links and its objects
+/// back exactly where they were - link order decides the order of arguments.
+#[derive(Clone)]
+pub struct FrameSnapshot {
+    pub frame: Arc<RefCell<Frame>>,
+    pub index: usize,
+    pub links: Vec<(usize, Arc<RefCell<Link>>)>,
+    pub objects: Vec<(Arc<RefCell<Machine>>, usize, ObjectCell)>,
+}
+
+/// Reversible change of a blueprint.
+pub enum Edit {
+    InsertFrame(FrameSnapshot),
+    RemoveFrame(FrameSnapshot),
+    Reshape {
+        frame: Arc<RefCell<Frame>>,
+        before: (WorldPoint, WorldSize),
+        after: (WorldPoint, WorldSize),
+    },
+    InsertLink(Arc<RefCell<Link>>),
+    SetOrder {
+        link: Arc<RefCell<Link>>,
+        before: i32,
+        after: i32,
+    },
+    SetText {
+        object: ObjectCell,
+        before: String,
+        after: String,
+    },
+}
+
+impl Edit {
+    fn apply(&self, blueprint: &mut Blueprint) {
+        match self {
+            &Edit::InsertFrame(ref snapshot) => blueprint.insert_frame(snapshot),
+            &Edit::RemoveFrame(ref snapshot) => {
+                blueprint.remove_frame(&snapshot.frame);
+            }
+            &Edit::Reshape { ref frame, after, .. } => reshape(frame, after),
+            &Edit::InsertLink(ref link) => blueprint.links.push(link.clone()),
+            &Edit::SetOrder { ref link, after, .. } => link.borrow_mut().order = after,
+            &Edit::SetText { ref object, ref after, .. } => set_text(object, after),
+        }
+    }
+
+    fn revert(&self, blueprint: &mut Blueprint) {
+        match self {
+            &Edit::InsertFrame(ref snapshot) => {
+                blueprint.remove_frame(&snapshot.frame);
+            }
+            &Edit::RemoveFrame(ref snapshot) => blueprint.insert_frame(snapshot),
+            &Edit::Reshape { ref frame, before, .. } => reshape(frame, before),
+            &Edit::InsertLink(ref link) => blueprint.links.retain(|other| !Arc::ptr_eq(other, link)),
+            &Edit::SetOrder { ref link, before, .. } => link.borrow_mut().order = before,
+            &Edit::SetText { ref object, ref before, .. } => set_text(object, before),
+        }
+    }
+
+    /// Merges `next` into this edit if both are part of the same gesture (typing into one text).
+    fn absorb(&mut self, next: &Edit) -> bool {
+        match (self, next) {
+            (&mut Edit::SetText { ref object, ref mut after, .. },
+             &Edit::SetText { object: ref next_object, after: ref next_after, .. }) => {
+                if Arc::ptr_eq(object, next_object) {
+                    *after = next_after.clone();
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+fn reshape(frame: &Arc<RefCell<Frame>>, shape: (WorldPoint, WorldSize)) {
+    let mut frame = frame.borrow_mut();
+    frame.pos = shape.0;
+    frame.size = shape.1;
+}
+
+fn set_text(object: &ObjectCell, text: &String) {
+    if let Some(contents) = object.borrow_mut().data.downcast_mut::<String>() {
+        *contents = text.clone();
+    }
+}
+
+/// Undo & redo stacks of a blueprint.
+///
+/// Edits recorded between `begin` and `commit` form a single step - drags use this to collapse
+/// every intermediate position into one. Each client has its own open transaction, so gestures
+/// of different clients never end up in the same step.
+pub struct History {
+    undo: Vec<Vec<Edit>>,
+    redo: Vec<Vec<Edit>>,
+    open: HashMap<i64, Vec<Edit>>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            open: HashMap::new(),
+        }
+    }
+
+    pub fn begin(&mut self, client: i64) {
+        self.open.entry(client).or_insert_with(Vec::new);
+    }
+
+    pub fn commit(&mut self, client: i64) {
+        if let Some(transaction) = self.open.remove(&client) {
+            if !transaction.is_empty() {
+                self.undo.push(transaction);
+            }
+        }
+    }
+
+    /// Drops the open transaction of `client` - for gestures that already restored the previous
+    /// state.
+    pub fn discard(&mut self, client: i64) {
+        self.open.remove(&client);
+    }
+
+    pub fn record(&mut self, client: i64, edit: Edit) {
+        self.redo.clear();
+        if let Some(transaction) = self.open.get_mut(&client) {
+            transaction.push(edit);
+            return;
+        }
+        if let Some(last) = self.undo.last_mut() {
+            if last.len() == 1 && last[0].absorb(&edit) {
+                return;
+            }
+        }
+        self.undo.push(vec![edit]);
+    }
+}
+
+impl Blueprint {
+    pub fn undo(&mut self, client: i64) {
+        self.history.commit(client);
+        if let Some(transaction) = self.history.undo.pop() {
+            for edit in transaction.iter().rev() {
+                edit.revert(self);
+            }
+            self.history.redo.push(transaction);
+        }
+    }
+
+    pub fn redo(&mut self, client: i64) {
+        self.history.commit(client);
+        if let Some(transaction) = self.history.redo.pop() {
+            for edit in transaction.iter() {
+                edit.apply(self);
+            }
+            self.history.undo.push(transaction);
+        }
+    }
+}
+
+struct UndoAction {
+    redo: bool,
+}
+
+impl Action for UndoAction {
+    fn start(
+        self: Box<Self>,
+        vm: &mut Vm,
+        _: DisplayPoint,
+        _: WorldPoint,
+    ) -> Option<Box<TouchReceiver>> {
+        if let Some(blueprint) = vm.active_blueprint.upgrade() {
+            let mut blueprint = blueprint.borrow_mut();
+            if self.redo {
+                blueprint.redo(vm.client);
+            } else {
+                blueprint.undo(vm.client);
+            }
+        }
+        None
+    }
+}
+
+pub fn history_entries() -> Vec<Entry> {
+    vec![
+        Entry {
+            name: "Undo".to_string(),
+            color: None,
+            shortcuts: vec!["Ctrl+Z".to_string()],
+            action: Box::new(UndoAction { redo: false }),
+        },
+        Entry {
+            name: "Redo".to_string(),
+            color: None,
+            shortcuts: vec!["Ctrl+Shift+Z".to_string()],
+            action: Box::new(UndoAction { redo: true }),
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::*;
+    use fixtures;
+    use LinkTerminator;
+
+    #[test]
+    fn undo_and_redo_frame_removal() {
+        let (_vm, blueprint) = fixtures::blueprint("History");
+        let text = fixtures::frame(&blueprint, "Text", false);
+        let process = fixtures::frame(&blueprint, "Process", false);
+        let a = fixtures::param(&process, 0);
+        fixtures::link(&blueprint, a, LinkTerminator::Frame(text.clone()), 0);
+
+        let mut bp = blueprint.borrow_mut();
+        let snapshot = bp.remove_frame(&text).unwrap();
+        bp.history.record(0, Edit::RemoveFrame(snapshot));
+        assert_eq!(bp.frames.len(), 1);
+        assert_eq!(bp.links.len(), 0);
+        assert_eq!(bp.machines[0].borrow().objects.len(), 1);
+
+        bp.undo(0);
+        assert!(Arc::ptr_eq(&bp.frames[0], &text));
+        assert_eq!(bp.links.len(), 1);
+        assert!(bp.machines[0].borrow().find_object(&text).is_some());
+
+        bp.redo(0);
+        assert_eq!(bp.frames.len(), 1);
+        assert_eq!(bp.links.len(), 0);
+    }
+
+    /// Machines added after a deletion get an object when it's undone.
+    #[test]
+    fn undo_removal_on_new_machines() {
+        let (_vm, blueprint) = fixtures::blueprint("History");
+        let text = fixtures::frame(&blueprint, "Text", false);
+        let snapshot = blueprint.borrow_mut().remove_frame(&text).unwrap();
+        blueprint.borrow_mut().history.record(0, Edit::RemoveFrame(snapshot));
+        let added = Machine::new(&blueprint);
+
+        blueprint.borrow_mut().undo(0);
+        assert!(added.borrow().find_object(&text).is_some());
+    }
+
+    /// A link dragged by one client isn't committed by another client finishing its drag.
+    #[test]
+    fn transactions_are_per_client() {
+        let (_vm, blueprint) = fixtures::blueprint("History");
+        let text = fixtures::frame(&blueprint, "Text", false);
+        let b = LinkTerminator::Point(WorldPoint::new(0., 0.));
+        let link = fixtures::link(&blueprint, LinkTerminator::Frame(text.clone()), b, 0);
+        let mut bp = blueprint.borrow_mut();
+        bp.history.begin(1);
+        bp.history.record(1, Edit::InsertLink(link));
+
+        let size = text.borrow().size;
+        text.borrow_mut().pos = WorldPoint::new(10., 0.);
+        bp.history.begin(0);
+        bp.history.record(0, Edit::Reshape {
+            frame: text.clone(),
+            before: (WorldPoint::new(0., 0.), size),
+            after: (WorldPoint::new(10., 0.), size),
+        });
+        bp.history.commit(0);
+
+        bp.links.clear();
+        bp.history.discard(1);
+        bp.undo(0);
+        assert_eq!(text.borrow().pos.x, 0.);
+        bp.redo(0);
+        assert_eq!(text.borrow().pos.x, 10.);
+        assert_eq!(bp.links.len(), 0);
+    }
+
+    #[test]
+    fn typing_is_one_step() {
+        let (_vm, blueprint) = fixtures::blueprint("History");
+        let text = fixtures::frame(&blueprint, "Text", false);
+        let object = blueprint.borrow().machines[0].borrow().get_object(&text);
+        let mut bp = blueprint.borrow_mut();
+        for &(before, after) in [("", "a"), ("a", "ab"), ("ab", "abc")].iter() {
+            set_text(&object, &after.to_string());
+            bp.history.record(0, Edit::SetText {
+                object: object.clone(),
+                before: before.to_string(),
+                after: after.to_string(),
+            });
+        }
+        bp.undo(0);
+        assert_eq!(object.borrow().data.downcast_ref::<String>().unwrap(), "");
+        bp.redo(0);
+        assert_eq!(object.borrow().data.downcast_ref::<String>().unwrap(), "abc");
+    }
+}
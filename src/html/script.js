@@ -1,4 +1,5 @@
-var cmds = [];
+// Display lists of the layers, bottom to top. Only the changed ones are sent.
+var layers = [];
 
 document.body.style.margin = '0';
 document.body.style.overflow = 'hidden';
@@ -17,9 +18,11 @@ function draw() {
   ctx.fillStyle = '#ddd';
   ctx.fillRect(0, 0, canvas.width, canvas.height);
   ctx.fillStyle = '#000';
-  ctx.save();
-  cmds.forEach(drawCommand);
-  ctx.restore();
+  layers.forEach(function(cmds) {
+    ctx.save();
+    cmds.forEach(drawCommand);
+    ctx.restore();
+  });
   socket.send(JSON.stringify({
     "type": "render_done",
     "time": performance.now()
@@ -61,21 +64,120 @@ function fill() { ctx.fill(); }
 function stroke() { ctx.stroke(); }
 function clip() { ctx.clip(); }
 
+// Decoder of BinaryCanvas commands. Each opcode lists the fields of the command it produces - "f"
+// fields are 32-bit floats, "s" strings, "b" booleans and "d" arrays of floats.
+var opcodes = [
+  ["translate", "fx", "fy"],
+  ["fillText", "stext", "fx", "fy"],
+  ["fillRect", "fx", "fy", "fw", "fh"],
+  ["rect", "fx", "fy", "fw", "fh"],
+  ["arc", "fx", "fy", "fr", "falpha", "fbeta", "bclockwise"],
+  ["ellipse", "fx", "fy", "frx", "fry", "frotation", "falpha", "fbeta", "banticlockwise"],
+  ["moveTo", "fx", "fy"],
+  ["lineTo", "fx", "fy"],
+  ["setLineDash", "dval"],
+  ["rotate", "fval"],
+  ["scale", "fval"],
+  ["fillStyle", "sval"],
+  ["textAlign", "sval"],
+  ["textBaseline", "sval"],
+  ["lineWidth", "fval"],
+  ["strokeStyle", "sval"],
+  ["font", "sval"],
+  ["save"],
+  ["restore"],
+  ["beginPath"],
+  ["closePath"],
+  ["fill"],
+  ["stroke"],
+  ["clip"],
+];
+
+var utf8 = new TextDecoder("utf-8");
+
+function BinaryReader(buffer) {
+  var view = new DataView(buffer);
+  var bytes = new Uint8Array(buffer);
+  var pos = 0;
+  var reader = {};
+  reader.length = function() {
+    var n = 0, shift = 0, b;
+    do {
+      b = bytes[pos++];
+      n += (b & 0x7f) * Math.pow(2, shift);
+      shift += 7;
+    } while (b & 0x80);
+    return n;
+  };
+  reader.float = function() {
+    var f = view.getFloat32(pos, true);
+    pos += 4;
+    return f;
+  };
+  reader.string = function() {
+    var n = reader.length();
+    var s = utf8.decode(bytes.subarray(pos, pos + n));
+    pos += n;
+    return s;
+  };
+  reader.commands = function(end) {
+    var cmds = [];
+    while (pos < end) {
+      var op = opcodes[bytes[pos++]];
+      var cmd = { "type": op[0] };
+      for (var i = 1; i < op.length; ++i) {
+        var name = op[i].substr(1);
+        switch (op[i][0]) {
+          case "f": cmd[name] = reader.float(); break;
+          case "s": cmd[name] = reader.string(); break;
+          case "b": cmd[name] = bytes[pos++] != 0; break;
+          case "d":
+            var dash = [];
+            for (var n = reader.length(); n > 0; --n) dash.push(reader.float());
+            cmd[name] = dash;
+            break;
+        }
+      }
+      cmds.push(cmd);
+    }
+    return cmds;
+  };
+  reader.pos = function() { return pos; };
+  return reader;
+}
+
+// Binary counterpart of the "layers" message.
+function ReadLayers(buffer) {
+  var reader = BinaryReader(buffer);
+  layers.length = reader.length();
+  for (var changed = reader.length(); changed > 0; --changed) {
+    var i = reader.length();
+    var n = reader.length();
+    layers[i] = reader.commands(reader.pos() + n);
+  }
+}
+
 var socket = undefined;
 var binds = [
   {"html": "onmousedown", "mvm": "mouse_down", "x": "clientX", "y": "clientY", "button": "button"},
   {"html": "onmousemove", "mvm": "mouse_move", "x": "clientX", "y": "clientY"},
   {"html": "onmouseup",   "mvm": "mouse_up",   "x": "clientX", "y": "clientY", "button": "button"},
   {"html": "onwheel",     "mvm": "wheel",     "x": "deltaX",  "y": "deltaY"},
-  {"html": "onkeydown",   "mvm": "key_down",   "code": "code", "key": "key"},
+  {"html": "onkeydown",   "mvm": "key_down",   "code": "code", "key": "key", "ctrl": "ctrlKey", "shift": "shiftKey"},
   {"html": "onkeyup",     "mvm": "key_up",     "code": "code", "key": "key"},
   {"html": "oncontextmenu"},
 ];
 
 function SocketMessage(e) {
+  if (e.data instanceof ArrayBuffer) {
+    ReadLayers(e.data);
+    draw();
+    return;
+  }
   var msg = JSON.parse(e.data);
-  if (Array.isArray(msg)) {
-    cmds = msg;
+  if (msg.type === "layers") {
+    layers.length = msg.count;
+    msg.changed.forEach(function(change) { layers[change[0]] = change[1]; });
     draw();
   } else {
     if (msg.type === "measureText") {
@@ -102,6 +204,7 @@ function Reconnect() {
 
 function Connect() {
   socket = new WebSocket("ws://localhost:8081/");
+  socket.binaryType = "arraybuffer";
   socket.onmessage = SocketMessage;
   socket.onopen = SocketOpen;
   socket.onerror = Reconnect;
@@ -113,14 +216,19 @@ function SocketClose() {
   Reconnect();
 };
 
+// The canvas is backed by device pixels, so the server knows their real size.
 function WindowResize(e) {
+  var ratio = devicePixelRatio || 1;
   socket.send(JSON.stringify({
     "type": "size",
-    "width": innerWidth,
-    "height": innerHeight
+    "width": innerWidth * ratio,
+    "height": innerHeight * ratio,
+    "dpi": 96 * ratio
   }));
-  canvas.width = innerWidth;
-  canvas.height = innerHeight;
+  canvas.width = innerWidth * ratio;
+  canvas.height = innerHeight * ratio;
+  canvas.style.width = innerWidth + 'px';
+  canvas.style.height = innerHeight + 'px';
   ctx.font = '20px Iosevka';
   draw();
 };
@@ -132,6 +240,7 @@ function Bind(bind) {
       for (var key in bind) {
 	if (key == "html" || key == "mvm") continue;
 	o[key] = e[bind[key]];
+	if (bind[key] == "clientX" || bind[key] == "clientY") o[key] *= devicePixelRatio || 1;
       }
       socket.send(JSON.stringify(o));
     }
@@ -142,6 +251,7 @@ function Bind(bind) {
 
 function SocketOpen(e) {
   socket.onerror = undefined;
+  socket.send(JSON.stringify({ "type": "protocol", "binary": true }));
   window.onresize = WindowResize;
   window.onresize();
   binds.forEach(Bind);
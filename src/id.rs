@@ -0,0 +1,24 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Persistent identifier of a blueprint, frame, machine or link.
+///
+/// Ids are unique across the whole VM and never change, so unlike positions in the `Vec`s that
+/// hold these objects they can be stored in files and handed to external tools.
+pub type Id = u64;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+pub fn new_id() -> Id {
+    NEXT_ID.fetch_add(1, Ordering::SeqCst) as Id
+}
+
+/// Makes sure that an id loaded from a file is never handed out by `new_id`.
+pub fn reserve_id(id: Id) {
+    let mut next = NEXT_ID.load(Ordering::SeqCst);
+    while next <= id as usize {
+        match NEXT_ID.compare_exchange(next, id as usize + 1, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => break,
+            Err(actual) => next = actual,
+        }
+    }
+}
@@ -0,0 +1,225 @@
+extern crate serde_json;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use self::serde_json::Value;
+
+use id::Id;
+
+/// Step on the path from the root of vm.json to the value that couldn't be loaded.
+#[derive(Debug, Clone)]
+pub enum Location {
+    Blueprint(usize),
+    Frame(usize),
+    Link(usize),
+    Machine(usize),
+    Object(usize),
+    Task(usize),
+    Field(&'static str),
+    Migration(u64),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Location::Blueprint(i) => write!(f, "blueprint {}", i),
+            &Location::Frame(i) => write!(f, "frame {}", i),
+            &Location::Link(i) => write!(f, "link {}", i),
+            &Location::Machine(i) => write!(f, "machine {}", i),
+            &Location::Object(i) => write!(f, "object {}", i),
+            &Location::Task(i) => write!(f, "task {}", i),
+            &Location::Field(name) => write!(f, "field \"{}\"", name),
+            &Location::Migration(from) => write!(f, "migration from version {}", from),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadErrorKind {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Missing,
+    WrongType(&'static str),
+    OutOfRange(u64, usize),
+    /// Name of the type and names of all registered types.
+    UnknownType(String, Vec<String>),
+    UnknownTerminator(String),
+    BadData(String),
+    UnsupportedVersion(u64),
+    UnknownId(Id),
+}
+
+#[derive(Debug)]
+pub struct LoadError {
+    pub location: Vec<Location>,
+    pub kind: LoadErrorKind,
+}
+
+pub type LoadResult<T> = Result<T, LoadError>;
+
+impl LoadError {
+    pub fn new(kind: LoadErrorKind) -> LoadError {
+        LoadError {
+            location: Vec::new(),
+            kind: kind,
+        }
+    }
+
+    /// Prepends `location` - errors are built from the innermost value outwards.
+    pub fn at(mut self, location: Location) -> LoadError {
+        self.location.insert(0, location);
+        self
+    }
+
+    pub fn is_missing_file(&self) -> bool {
+        match self.kind {
+            LoadErrorKind::Io(ref err) => err.kind() == io::ErrorKind::NotFound,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, location) in self.location.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", location)?;
+        }
+        if !self.location.is_empty() {
+            write!(f, ": ")?;
+        }
+        match self.kind {
+            LoadErrorKind::Io(ref err) => write!(f, "{}", err),
+            LoadErrorKind::Json(ref err) => write!(f, "{}", err),
+            LoadErrorKind::Missing => write!(f, "missing"),
+            LoadErrorKind::WrongType(expected) => write!(f, "expected {}", expected),
+            LoadErrorKind::OutOfRange(index, len) => {
+                write!(f, "index {} out of range (there are {})", index, len)
+            }
+            LoadErrorKind::UnknownType(ref name, ref known) => write!(
+                f,
+                "unknown type \"{}\" (known types: {})",
+                name,
+                known.join(", ")
+            ),
+            LoadErrorKind::UnknownTerminator(ref name) => {
+                write!(f, "unknown link terminator \"{}\"", name)
+            }
+            LoadErrorKind::BadData(ref msg) => write!(f, "{}", msg),
+            LoadErrorKind::UnknownId(id) => write!(f, "nothing has id {}", id),
+            LoadErrorKind::UnsupportedVersion(version) => {
+                write!(f, "format version {} is newer than this build supports", version)
+            }
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn description(&self) -> &str {
+        "couldn't load VM state"
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> LoadError {
+        LoadError::new(LoadErrorKind::Io(err))
+    }
+}
+
+impl From<serde_json::Error> for LoadError {
+    fn from(err: serde_json::Error) -> LoadError {
+        LoadError::new(LoadErrorKind::Json(err))
+    }
+}
+
+pub trait LoadContext {
+    fn at(self, location: Location) -> Self;
+}
+
+impl<T> LoadContext for LoadResult<T> {
+    fn at(self, location: Location) -> LoadResult<T> {
+        self.map_err(|err| err.at(location))
+    }
+}
+
+pub fn field<'a>(json: &'a Value, name: &'static str) -> LoadResult<&'a Value> {
+    json.get(name).ok_or_else(|| {
+        LoadError::new(LoadErrorKind::Missing).at(Location::Field(name))
+    })
+}
+
+fn typed<'a, T, F>(json: &'a Value, name: &'static str, expected: &'static str, f: F) -> LoadResult<T>
+where
+    F: Fn(&'a Value) -> Option<T>,
+{
+    f(field(json, name)?).ok_or_else(|| {
+        LoadError::new(LoadErrorKind::WrongType(expected)).at(Location::Field(name))
+    })
+}
+
+pub fn get_array<'a>(json: &'a Value, name: &'static str) -> LoadResult<&'a Vec<Value>> {
+    typed(json, name, "an array", Value::as_array)
+}
+
+pub fn get_str<'a>(json: &'a Value, name: &'static str) -> LoadResult<&'a str> {
+    typed(json, name, "a string", Value::as_str)
+}
+
+pub fn get_bool(json: &Value, name: &'static str) -> LoadResult<bool> {
+    typed(json, name, "a boolean", Value::as_bool)
+}
+
+pub fn get_i64(json: &Value, name: &'static str) -> LoadResult<i64> {
+    typed(json, name, "an integer", Value::as_i64)
+}
+
+/// Reads a `[x, y]` pair, as written by `SerializablePoint2D` and `SerializableSize2D`.
+pub fn as_pair(json: &Value) -> LoadResult<(f64, f64)> {
+    let pair = json.as_array().and_then(|array| if array.len() == 2 {
+        Some((array[0].as_f64(), array[1].as_f64()))
+    } else {
+        None
+    });
+    match pair {
+        Some((Some(x), Some(y))) => Ok((x, y)),
+        _ => Err(LoadError::new(LoadErrorKind::WrongType("a pair of numbers"))),
+    }
+}
+
+pub fn get_pair(json: &Value, name: &'static str) -> LoadResult<(f64, f64)> {
+    as_pair(field(json, name)?).at(Location::Field(name))
+}
+
+/// Interprets `json` as an index into a collection of `len` elements.
+pub fn as_index(json: &Value, len: usize) -> LoadResult<usize> {
+    let index = json.as_u64().ok_or_else(|| {
+        LoadError::new(LoadErrorKind::WrongType("an index"))
+    })?;
+    if index as usize >= len {
+        return Err(LoadError::new(LoadErrorKind::OutOfRange(index, len)));
+    }
+    Ok(index as usize)
+}
+
+pub fn get_index(json: &Value, name: &'static str, len: usize) -> LoadResult<usize> {
+    as_index(field(json, name)?, len).at(Location::Field(name))
+}
+
+pub fn as_id(json: &Value) -> LoadResult<Id> {
+    json.as_u64().ok_or_else(|| {
+        LoadError::new(LoadErrorKind::WrongType("an id"))
+    })
+}
+
+pub fn get_id(json: &Value, name: &'static str) -> LoadResult<Id> {
+    as_id(field(json, name)?).at(Location::Field(name))
+}
+
+/// Error for an id that was already used by an earlier element of the same collection.
+pub fn duplicate_id(id: Id) -> LoadError {
+    LoadError::new(LoadErrorKind::BadData(format!("duplicate id {}", id)))
+}
@@ -3,73 +3,159 @@ extern crate serde_json;
 
 use std::sync::{Arc, Weak};
 use std::cell::RefCell;
+use std::collections::HashMap;
 
-use blueprint::Blueprint;
+use blueprint::{Blueprint, find_frame};
 use Object;
 use Frame;
+use RunArg;
 
 pub struct Machine {
+    pub id: Id,
     pub blueprint: Weak<RefCell<Blueprint>>,
     pub objects: Vec<Arc<RefCell<Object>>>,
+    /// Objects passed to the parameter frames (keyed by frame id) of a nested machine.
+    pub arguments: HashMap<Id, RunArg>,
+    /// Included in "Run on selected machines".
+    pub selected: bool,
+    /// Progress of the last run on several machines.
+    pub status: Option<MachineStatus>,
 }
 
-use serde::ser::{Serialize, Serializer};
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MachineStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+use serde::ser::{Serialize, Serializer, SerializeStruct};
 use SerializableVec;
+use load::*;
+use id::*;
 
 impl Serialize for Machine {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        serializer.serialize_some(&SerializableVec(&self.objects))
+        let mut s = serializer.serialize_struct("Machine", 2)?;
+        s.serialize_field("id", &self.id)?;
+        s.serialize_field("objects", &SerializableVec(&self.objects))?;
+        s.end()
     }
 }
 
 impl Machine {
     pub fn new(blueprint: &Arc<RefCell<Blueprint>>) -> Arc<RefCell<Machine>> {
-        // TODO: initialize objects
-        let machine = Arc::new(RefCell::new(Machine {
-            blueprint: Arc::downgrade(blueprint),
-            objects: Vec::new(),
-        }));
+        Machine::with_id(new_id(), blueprint)
+    }
+
+    pub fn with_id(id: Id, blueprint: &Arc<RefCell<Blueprint>>) -> Arc<RefCell<Machine>> {
+        let machine = Machine::detached(id, blueprint);
 
         for frame_rc in blueprint.borrow().frames.iter() {
-            let frame = frame_rc.borrow();
-            if frame.global {
+            if frame_rc.borrow().global {
                 continue;
             }
-            let mut object = Object {
-                machine: Arc::downgrade(&machine),
-                frame: frame_rc.clone(),
-                execute: false,
-                data: Box::new(()),
-            };
-            (frame.typ.init)(&mut object);
-            machine.borrow_mut().push(object);
+            Machine::init_object(&machine, frame_rc);
         }
 
         blueprint.borrow_mut().machines.push(machine.clone());
+        blueprint.borrow_mut().machines_by_id.insert(id, machine.clone());
         return machine;
     }
 
-    pub fn load_json(this: &Arc<RefCell<Machine>>, json: &serde_json::Value) {
-        let arr = json.as_array().unwrap();
+    /// Machine owned by an object that instantiates `blueprint`.
+    ///
+    /// It isn't listed in `blueprint.machines` and has its own objects for all frames, global ones
+    /// included.
+    pub fn nested(id: Id, blueprint: &Arc<RefCell<Blueprint>>) -> Arc<RefCell<Machine>> {
+        let machine = Machine::detached(id, blueprint);
+        Machine::sync(&machine, &blueprint.borrow());
+        machine
+    }
+
+    fn detached(id: Id, blueprint: &Arc<RefCell<Blueprint>>) -> Arc<RefCell<Machine>> {
+        reserve_id(id);
+        Arc::new(RefCell::new(Machine {
+            id: id,
+            blueprint: Arc::downgrade(blueprint),
+            objects: Vec::new(),
+            arguments: HashMap::new(),
+            selected: false,
+            status: None,
+        }))
+    }
+
+    pub fn init_object(this: &Arc<RefCell<Machine>>, frame_rc: &Arc<RefCell<Frame>>) {
+        let frame = frame_rc.borrow();
+        let mut object = Object {
+            machine: Arc::downgrade(this),
+            frame: frame_rc.clone(),
+            execute: false,
+            data: Box::new(()),
+        };
+        frame.typ.init(&mut object);
+        this.borrow_mut().push(object);
+    }
+
+    /// Brings the objects of a nested machine up to date with the frames of `blueprint`.
+    pub fn sync(this: &Arc<RefCell<Machine>>, blueprint: &Blueprint) {
+        this.borrow_mut().objects.retain(|object| {
+            blueprint.frame(object.borrow().frame.borrow().id).is_some()
+        });
+        for frame_rc in blueprint.frames.iter() {
+            if this.borrow().find_object(frame_rc).is_none() {
+                Machine::init_object(this, frame_rc);
+            }
+        }
+    }
+
+    pub fn load_json(this: &Arc<RefCell<Machine>>, json: &serde_json::Value) -> LoadResult<()> {
+        let arr = get_array(json, "objects")?;
         let mut machine = this.borrow_mut();
         let bp_rc = machine.blueprint.upgrade().unwrap();
         let bp = bp_rc.borrow();
-        for object_json in arr.iter() {
-            let frame_idx = object_json.get("frame").unwrap().as_u64().unwrap();
-            let frame_rc = bp.frames[frame_idx as usize].clone();
-            let typ = frame_rc.borrow().typ;
-            let execute = object_json.get("execute").unwrap().as_bool().unwrap();
-            let mut object = Object {
-                machine: Arc::downgrade(this),
-                frame: frame_rc,
-                execute: execute,
-                data: Box::new(()),
-            };
-            let data = object_json.get("data").unwrap();
-            let data = serde_json::from_value(data.clone()).ok().unwrap();
-            (typ.deserialize)(&mut object, data);
+        // Objects created by `Machine::new` are kept only for frames missing from the file.
+        let initialized = std::mem::replace(&mut machine.objects, Vec::new());
+        for (i, object_json) in arr.iter().enumerate() {
+            let object = Machine::load_object_json(this, &bp, object_json)
+                .at(Location::Object(i))
+                .at(Location::Field("objects"))?;
             machine.push(object);
         }
+        for object in initialized.into_iter() {
+            if machine.find_object(&object.borrow().frame).is_none() {
+                machine.objects.push(object.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn load_object_json(
+        this: &Arc<RefCell<Machine>>,
+        bp: &Blueprint,
+        object_json: &serde_json::Value,
+    ) -> LoadResult<Object> {
+        let frame_rc = get_id(object_json, "frame")
+            .and_then(|id| find_frame(bp, id))
+            .at(Location::Field("frame"))?;
+        let typ = frame_rc.borrow().typ.clone();
+        let execute = get_bool(object_json, "execute")?;
+        let mut object = Object {
+            machine: Arc::downgrade(this),
+            frame: frame_rc,
+            execute: execute,
+            data: Box::new(()),
+        };
+        let data = field(object_json, "data")?;
+        let data: Vec<u8> = serde_json::from_value(data.clone()).map_err(|_| {
+            LoadError::new(LoadErrorKind::WrongType("an array of bytes"))
+                .at(Location::Field("data"))
+        })?;
+        typ.deserialize(&mut object, data).map_err(|msg| {
+            LoadError::new(LoadErrorKind::BadData(msg)).at(Location::Field("data"))
+        })?;
+        Ok(object)
     }
 
     pub fn push(&mut self, object: Object) {
@@ -77,11 +163,30 @@ impl Machine {
     }
 
     pub fn get_object(&self, frame_rc: &Arc<RefCell<Frame>>) -> Arc<RefCell<Object>> {
+        self.find_object(frame_rc).unwrap()
+    }
+
+    pub fn find_object(&self, frame_rc: &Arc<RefCell<Frame>>) -> Option<Arc<RefCell<Object>>> {
         self.objects
             .iter()
             .find(|o| Arc::ptr_eq(&o.borrow().frame, frame_rc))
-            .unwrap()
-            .clone()
+            .cloned()
+    }
+
+    /// Object of `frame_rc` as seen by this machine - objects of global frames live on the first
+    /// machine of the blueprint.
+    pub fn object_for(&self, frame_rc: &Arc<RefCell<Frame>>) -> Option<Arc<RefCell<Object>>> {
+        if let Some(object) = self.find_object(frame_rc) {
+            return Some(object);
+        }
+        if !frame_rc.borrow().global {
+            return None;
+        }
+        let blueprint = frame_rc.borrow().blueprint.upgrade();
+        blueprint.and_then(|blueprint| {
+            let blueprint = blueprint.borrow();
+            blueprint.machines.get(0).and_then(|first| first.borrow().find_object(frame_rc))
+        })
     }
 
     pub fn with_object<F: FnMut(&mut Object)>(&mut self, frame_rc: &Arc<RefCell<Frame>>, mut f: F) {
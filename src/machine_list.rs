@@ -0,0 +1,241 @@
+use std::sync::{Arc, Weak};
+use std::cell::RefCell;
+use std::f64::consts::PI;
+
+use canvas::Canvas;
+use machine::{Machine, MachineStatus};
+use menu::*;
+use vm::Vm;
+use Frame;
+use TouchReceiver;
+use WorldPoint;
+use DisplayPoint;
+use PARAM_RADIUS;
+
+const ROW_HEIGHT: f64 = PARAM_RADIUS * 3.;
+/// Width of the status column right of the machine circles.
+pub const STATUS_WIDTH: f64 = 24.;
+
+fn status_style(status: MachineStatus) -> (&'static str, &'static str) {
+    match status {
+        MachineStatus::Queued => ("queued", "#959ba5"),
+        MachineStatus::Running => ("running", "#3e64a3"),
+        MachineStatus::Succeeded => ("done", "#2e8b57"),
+        MachineStatus::Failed => ("failed", "#c0392b"),
+    }
+}
+
+/// Machines of the active blueprint as circles, with the status of their last run next to them.
+pub fn draw_machine_list(vm: &Vm, c: &mut Canvas) {
+    let corner = vm.overlay_corner();
+    let blueprint = vm.active_blueprint.upgrade().unwrap();
+    let blueprint = blueprint.borrow();
+    let active_machine = blueprint.active_machine.upgrade().unwrap();
+    c.textAlign("left");
+    c.textBaseline("middle");
+    for (i, machine) in blueprint.machines.iter().enumerate() {
+        let top = corner.y + ROW_HEIGHT * i as f64;
+        let fill_style = if Arc::ptr_eq(machine, &active_machine) {
+            "#3e64a3"
+        } else {
+            "#959ba5"
+        };
+        c.fillStyle(fill_style);
+        c.fillCircle(corner.x, top, PARAM_RADIUS);
+        let machine = machine.borrow();
+        if machine.selected {
+            c.strokeStyle(fill_style);
+            c.lineWidth(0.5);
+            c.beginPath()
+                .arc(corner.x, top, PARAM_RADIUS * 1.5, 0., PI * 2., true)
+                .stroke();
+        }
+        if let Some(status) = machine.status {
+            let (text, color) = status_style(status);
+            c.fillStyle(color);
+            c.fillText(text, corner.x + PARAM_RADIUS * 2., top);
+        }
+    }
+}
+
+pub fn machine_at(vm: &Vm, d: DisplayPoint) -> Option<Arc<RefCell<Machine>>> {
+    let corner = vm.overlay_corner();
+    let top = corner.y - PARAM_RADIUS;
+    if d.x < corner.x - PARAM_RADIUS || d.x > corner.x + PARAM_RADIUS || d.y < top {
+        return None;
+    }
+    let row = ((d.y - top) / ROW_HEIGHT) as usize;
+    let blueprint = vm.active_blueprint.upgrade().unwrap();
+    let machine = blueprint.borrow().machines.get(row).cloned();
+    machine
+}
+
+/// Menu shown over a machine circle.
+pub fn machine_menu(machine: &Arc<RefCell<Machine>>) -> Menu {
+    let select = if machine.borrow().selected {
+        "Deselect"
+    } else {
+        "Select"
+    };
+    Menu {
+        entries: vec![
+            Entry {
+                name: "Activate".to_string(),
+                color: None,
+                shortcuts: vec!["LMB".to_string()],
+                action: Box::new(ActivateMachineAction { machine: Arc::downgrade(machine) }),
+            },
+            Entry {
+                name: select.to_string(),
+                color: None,
+                shortcuts: vec!["MMB".to_string()],
+                action: Box::new(SelectMachineAction { machine: Arc::downgrade(machine) }),
+            },
+        ],
+        color: "#3e64a3".to_string(),
+    }
+}
+
+struct ActivateMachineAction {
+    machine: Weak<RefCell<Machine>>,
+}
+
+impl Action for ActivateMachineAction {
+    fn start(
+        self: Box<Self>,
+        _: &mut Vm,
+        _: DisplayPoint,
+        _: WorldPoint,
+    ) -> Option<Box<TouchReceiver>> {
+        if let Some(machine) = self.machine.upgrade() {
+            let blueprint = machine.borrow().blueprint.upgrade().unwrap();
+            blueprint.borrow_mut().activate(&machine);
+        }
+        None
+    }
+}
+
+struct SelectMachineAction {
+    machine: Weak<RefCell<Machine>>,
+}
+
+impl Action for SelectMachineAction {
+    fn start(
+        self: Box<Self>,
+        _: &mut Vm,
+        _: DisplayPoint,
+        _: WorldPoint,
+    ) -> Option<Box<TouchReceiver>> {
+        if let Some(machine) = self.machine.upgrade() {
+            let mut machine = machine.borrow_mut();
+            machine.selected = !machine.selected;
+        }
+        None
+    }
+}
+
+/// Runs a frame on every machine of its blueprint, or only on the selected ones.
+pub struct RunOnMachinesAction {
+    pub frame: Weak<RefCell<Frame>>,
+    pub selected_only: bool,
+}
+
+impl Action for RunOnMachinesAction {
+    fn start(
+        self: Box<Self>,
+        vm: &mut Vm,
+        _: DisplayPoint,
+        _: WorldPoint,
+    ) -> Option<Box<TouchReceiver>> {
+        let frame = match self.frame.upgrade() {
+            Some(frame) => frame,
+            None => return None,
+        };
+        let blueprint = frame.borrow().blueprint.upgrade().unwrap();
+        let machines: Vec<_> = blueprint
+            .borrow()
+            .machines
+            .iter()
+            .filter(|machine| !self.selected_only || machine.borrow().selected)
+            .cloned()
+            .collect();
+        if machines.is_empty() {
+            println!("No machines selected - select them with MMB on their circles");
+            return None;
+        }
+        vm.run_on_machines(&frame, &machines);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixtures;
+    use LinkTerminator;
+
+    /// Every machine runs `true` or `false`, depending on its own Command text.
+    #[test]
+    fn runs_on_every_machine() {
+        let (vm, blueprint) = fixtures::blueprint("Fleet");
+        let process = fixtures::frame(&blueprint, "Process", false);
+        let command = fixtures::frame(&blueprint, "Text", false);
+        let a = fixtures::param(&process, 0);
+        fixtures::link(&blueprint, a, LinkTerminator::Frame(command.clone()), 0);
+        for _ in 0..2 {
+            Machine::new(&blueprint);
+        }
+        let machines = blueprint.borrow().machines.clone();
+        assert_eq!(machines.len(), 3);
+        for (i, machine) in machines.iter().enumerate() {
+            let object = machine.borrow().find_object(&command).unwrap();
+            let text = if i == 1 { "false" } else { "true" };
+            object.borrow_mut().data = Box::new(text.to_string());
+        }
+
+        let mut vm = vm.borrow_mut();
+        vm.machine_concurrency = 2;
+        vm.run_on_machines(&process, &machines);
+        let statuses: Vec<_> = machines.iter().map(|machine| machine.borrow().status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                Some(MachineStatus::Running),
+                Some(MachineStatus::Running),
+                Some(MachineStatus::Queued),
+            ]
+        );
+        vm.run_until(|vm| vm.batches.is_empty());
+        let statuses: Vec<_> = machines.iter().map(|machine| machine.borrow().status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                Some(MachineStatus::Succeeded),
+                Some(MachineStatus::Failed),
+                Some(MachineStatus::Succeeded),
+            ]
+        );
+    }
+
+    /// Machines other than the first one see the objects of global frames.
+    #[test]
+    fn global_inputs_reach_every_machine() {
+        let (vm, blueprint) = fixtures::blueprint("Shared");
+        let process = fixtures::frame(&blueprint, "Process", false);
+        let command = fixtures::frame(&blueprint, "Text", true);
+        let a = fixtures::param(&process, 0);
+        fixtures::link(&blueprint, a, LinkTerminator::Frame(command.clone()), 0);
+        Machine::new(&blueprint);
+        blueprint.borrow().get_object(&command).borrow_mut().data = Box::new("true".to_string());
+        let machines = blueprint.borrow().machines.clone();
+
+        let mut vm = vm.borrow_mut();
+        vm.run_on_machines(&process, &machines);
+        vm.run_until(|vm| vm.batches.is_empty());
+        let statuses: Vec<_> = machines.iter().map(|machine| machine.borrow().status).collect();
+        assert_eq!(
+            statuses,
+            vec![Some(MachineStatus::Succeeded), Some(MachineStatus::Succeeded)]
+        );
+    }
+}
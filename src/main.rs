@@ -3,7 +3,7 @@ TODOs:
 - Pass the results of running process as events back to the main thread (DONE)
 
 
-- Implement multiple blueprints
+- Implement multiple blueprints (DONE)
 
 
 - Top-level blueprint instances hold global variables
@@ -23,12 +23,9 @@ TODOs:
 On hold:
 - Cleanups - a - lot
 - Menu improvements (draw background below menu entry name)
-- Multiple mouse handlers at the same time
 - Performance monitoring
 - Interactive error reporting
-- Move per-client parameters to separate struct
 - Serve Iosevka from hyper
-- Support concurrent access from many clients (different dpi and viewports)
 */
 
 extern crate hyper;
@@ -39,6 +36,8 @@ extern crate serde_json;
 mod http;
 mod canvas;
 mod json_canvas;
+mod binary_canvas;
+mod svg_canvas;
 mod touch;
 mod machine;
 mod blueprint;
@@ -46,6 +45,22 @@ mod vm;
 mod event;
 mod menu;
 mod process;
+mod load;
+mod migration;
+mod save;
+mod id;
+mod history;
+mod blueprint_list;
+mod machine_list;
+mod nested;
+mod types;
+mod scheduler;
+mod output;
+mod session;
+mod presence;
+mod rendering;
+#[cfg(test)]
+mod fixtures;
 
 use std::time::Instant;
 use std::thread;
@@ -55,6 +70,7 @@ use std::sync::{Arc, Weak};
 use std::cell::{RefCell, Ref, RefMut};
 use std::ops::Deref;
 use std::f64::consts::PI;
+use std::borrow::Cow;
 
 use canvas::*;
 use json_canvas::*;
@@ -65,6 +81,12 @@ use machine::*;
 use blueprint::*;
 use vm::*;
 use menu::*;
+use id::*;
+use history::*;
+use nested::*;
+use types::*;
+use output::*;
+use machine_list::RunOnMachinesAction;
 
 use serde::ser::{Serialize, Serializer, SerializeSeq, SerializeStruct, SerializeTuple,
                  SerializeTupleVariant};
@@ -159,6 +181,10 @@ trait TouchReceiver {
         world: WorldPoint,
     ) -> Option<Box<TouchReceiver>>;
     fn end_touch(self: Box<Self>, &mut Vm);
+    /// Frame edited by this touch - other clients can't edit it until the touch ends.
+    fn frame(&self) -> Option<Arc<RefCell<Frame>>> {
+        None
+    }
 }
 
 // TODO: rename to VisibleLayer
@@ -188,15 +214,22 @@ impl FrameParam {
 impl Visible for FrameParam {
     fn draw(&self, c: &mut Canvas) {
         let center = self.center();
-        let param = &self.frame.borrow().typ.parameters[self.param_index];
+        let param = self.frame.borrow().parameters()[self.param_index].clone();
         c.fillStyle("white");
         c.fillCircle(center.x, center.y, PARAM_RADIUS);
         c.fillStyle("black");
         c.fillText(
-            param.name,
+            param.name.as_ref(),
             center.x + PARAM_RADIUS + PARAM_SPACING,
             center.y,
         );
+        c.fillStyle("#959ba5");
+        c.textAlign("right");
+        c.fillText(
+            &param.kind.to_string(),
+            center.x - PARAM_RADIUS - PARAM_SPACING,
+            center.y,
+        );
     }
     fn make_menu(&self, d: DisplayPoint, w: WorldPoint) -> Option<Menu> {
         let center = self.center();
@@ -233,7 +266,7 @@ impl ConnectParamAction {
 impl Action for ConnectParamAction {
     fn start(
         self: Box<Self>,
-        _: &mut Vm,
+        vm: &mut Vm,
         d: DisplayPoint,
         w: WorldPoint,
     ) -> Option<Box<TouchReceiver>> {
@@ -241,13 +274,17 @@ impl Action for ConnectParamAction {
         let blueprint_weak = frame_param.frame.borrow().blueprint.clone();
         let blueprint_rc = blueprint_weak.upgrade().unwrap();
         let mut blueprint = blueprint_rc.borrow_mut();
+        let order = blueprint.next_order(&frame_param);
         let link_rc = Arc::new(RefCell::new(Link {
+            id: new_id(),
             blueprint: blueprint_weak,
             a: LinkTerminator::FrameParam(frame_param),
             b: LinkTerminator::Point(w),
-            order: 0,
+            order: order,
         }));
         blueprint.links.push(link_rc.clone());
+        blueprint.history.begin(vm.client);
+        blueprint.history.record(vm.client, Edit::InsertLink(link_rc.clone()));
         Some(Box::new(DragLink {
             side: LinkSide::B,
             link: link_rc,
@@ -259,12 +296,13 @@ impl Action for ConnectParamAction {
 impl Visible for Arc<RefCell<Frame>> {
     fn draw(&self, c: &mut Canvas) {
         let frame = self.borrow();
-        if frame.typ.parameters.len() > 0 {
+        let param_count = frame.parameters().len();
+        if param_count > 0 {
             c.strokeStyle("#888");
             c.beginPath();
             let last_frame_param = FrameParam {
                 frame: self.clone(),
-                param_index: frame.typ.parameters.len() - 1,
+                param_index: param_count - 1,
             };
             let last_param_center = last_frame_param.center();
             c.moveTo(last_param_center.x, last_param_center.y);
@@ -272,7 +310,7 @@ impl Visible for Arc<RefCell<Frame>> {
             c.lineTo(end.x, end.y);
             c.stroke();
         }
-        for param_index in 0..frame.typ.parameters.len() {
+        for param_index in 0..param_count {
             let frame_param = FrameParam {
                 frame: self.clone(),
                 param_index: param_index,
@@ -284,13 +322,17 @@ impl Visible for Arc<RefCell<Frame>> {
         c.translate(-frame.size.width / 2., -frame.size.height / 2.);
         c.fillRect(0., 0., frame.size.width, frame.size.height);
         c.fillStyle("black");
-        c.fillText(frame.typ.name, 0., 0.);
+        let blueprint_rc = frame.blueprint.upgrade().unwrap();
+        let blueprint = blueprint_rc.borrow();
+        let mut title = frame.title();
+        if blueprint.entry_frame().map_or(false, |entry| Arc::ptr_eq(&entry, self)) {
+            title.push_str(" (entry)");
+        }
+        c.fillText(title.as_ref(), 0., 0.);
         c.beginPath();
         c.rect(0., 0., frame.size.width, frame.size.height);
         c.clip();
-        let blueprint_rc = frame.blueprint.upgrade().unwrap();
-        let blueprint = blueprint_rc.borrow();
-        blueprint.with_object(self, |o| { (frame.typ.draw)(o, c); });
+        blueprint.with_object(self, |o| { frame.typ.draw(o, c); });
     }
     fn make_menu(&self, d: DisplayPoint, w: WorldPoint) -> Option<Menu> {
         let mut q;
@@ -300,7 +342,7 @@ impl Visible for Arc<RefCell<Frame>> {
             let frame = self.borrow();
             q = w - frame.pos;
             s = frame.size * 0.5;
-            param_count = frame.typ.parameters.len();
+            param_count = frame.parameters().len();
         }
         fn range_check(x: f64, range: f64) -> bool {
             x < range && x > -range
@@ -325,6 +367,7 @@ impl Visible for Arc<RefCell<Frame>> {
             } else {
                 "Resize"
             }.to_string();
+            let typ = self.borrow().typ.clone();
             Some(Menu {
                 entries: vec![
                     Entry {
@@ -339,13 +382,39 @@ impl Visible for Arc<RefCell<Frame>> {
                         shortcuts: vec!["Space".to_string()],
                         action: Box::new(RunAction::new(self)),
                     },
+                    Entry {
+                        name: "Run on all machines".to_string(),
+                        color: None,
+                        shortcuts: vec!["Ctrl+Space".to_string()],
+                        action: Box::new(RunOnMachinesAction {
+                            frame: Arc::downgrade(self),
+                            selected_only: false,
+                        }),
+                    },
+                    Entry {
+                        name: "Run on selected machines".to_string(),
+                        color: None,
+                        shortcuts: vec!["Ctrl+Shift+Space".to_string()],
+                        action: Box::new(RunOnMachinesAction {
+                            frame: Arc::downgrade(self),
+                            selected_only: true,
+                        }),
+                    },
                     Entry {
                         name: "Delete".to_string(),
                         color: None,
                         shortcuts: vec!["Delete".to_string()],
                         action: Box::new(DeleteFrameAction::new(self)),
                     },
-                ],
+                    Entry {
+                        name: "Make entry".to_string(),
+                        color: None,
+                        shortcuts: vec!["Enter".to_string()],
+                        action: Box::new(SetEntryAction { frame: Arc::downgrade(self) }),
+                    },
+                ].into_iter()
+                    .chain(typ.menu(self))
+                    .collect(),
                 color: "#888".to_string(),
             })
         } else {
@@ -400,11 +469,11 @@ impl Action for RunAction {
 }
 
 struct AddFrameAction {
-    typ: &'static Type,
+    typ: Arc<FrameType>,
 }
 
 impl AddFrameAction {
-    fn new(typ: &'static Type) -> AddFrameAction {
+    fn new(typ: Arc<FrameType>) -> AddFrameAction {
         AddFrameAction { typ: typ }
     }
 }
@@ -417,8 +486,14 @@ impl Action for AddFrameAction {
         w: WorldPoint,
     ) -> Option<Box<TouchReceiver>> {
         let blueprint = vm.active_blueprint.upgrade().unwrap();
-        let frame = Frame::new(self.typ, &blueprint, true);
+        let frame = Frame::new(self.typ.clone(), &blueprint, true);
         frame.borrow_mut().pos = w;
+        {
+            let mut blueprint = blueprint.borrow_mut();
+            let snapshot = blueprint.frame_snapshot(&frame).unwrap();
+            blueprint.history.begin(vm.client);
+            blueprint.history.record(vm.client, Edit::InsertFrame(snapshot));
+        }
 
         Box::new(DragFrameAction::new(&frame, DragMode::Drag, DragMode::Drag)).start(vm, d, w)
     }
@@ -437,7 +512,7 @@ impl DeleteFrameAction {
 impl Action for DeleteFrameAction {
     fn start(
         self: Box<Self>,
-        _: &mut Vm,
+        vm: &mut Vm,
         d: DisplayPoint,
         w: WorldPoint,
     ) -> Option<Box<TouchReceiver>> {
@@ -447,35 +522,15 @@ impl Action for DeleteFrameAction {
             return None;
         }
         let frame = frame.unwrap();
+        if let Err(err) = vm.check_editable(&frame) {
+            println!("{}", err);
+            return None;
+        }
         let blueprint = frame.borrow().blueprint.upgrade();
         if let Some(blueprint) = blueprint {
             let mut blueprint = blueprint.borrow_mut();
-            if let Some((index, _)) = blueprint.frames.iter().enumerate().find(|x| {
-                Arc::ptr_eq(x.1, &frame)
-            })
-            {
-                blueprint.frames.swap_remove(index);
-
-                blueprint.links.retain(|link_rc| {
-                    fn side_retain(f: &Arc<RefCell<Frame>>, t: &LinkTerminator) -> bool {
-                        match t {
-                            &LinkTerminator::Frame(ref other_frame) => !Arc::ptr_eq(f, other_frame),
-                            &LinkTerminator::FrameParam(ref frame_param) => {
-                                !Arc::ptr_eq(f, &frame_param.frame)
-                            }
-                            _ => true,
-                        }
-                    }
-                    let link = link_rc.borrow();
-                    return side_retain(&frame, &link.a) && side_retain(&frame, &link.b);
-                });
-                for machine in blueprint.machines.iter() {
-                    let mut machine = machine.borrow_mut();
-                    machine.objects.retain(|o_rc| {
-                        !Arc::ptr_eq(&o_rc.borrow().frame, &frame)
-                    });
-                }
-                let frame = Arc::try_unwrap(frame).ok().unwrap();
+            if let Some(snapshot) = blueprint.remove_frame(&frame) {
+                blueprint.history.record(vm.client, Edit::RemoveFrame(snapshot));
             }
         }
         None
@@ -505,22 +560,42 @@ impl DragFrameAction {
 impl Action for DragFrameAction {
     fn start(
         self: Box<Self>,
-        _: &mut Vm,
+        vm: &mut Vm,
         d: DisplayPoint,
         w: WorldPoint,
     ) -> Option<Box<TouchReceiver>> {
+        let frame_rc = match self.frame.upgrade() {
+            Some(frame_rc) => frame_rc,
+            None => return None,
+        };
+        if let Err(err) = vm.check_editable(&frame_rc) {
+            println!("{}", err);
+            return None;
+        }
+        let (start_pos, start_size) = {
+            let frame = frame_rc.borrow();
+            if let Some(blueprint) = frame.blueprint.upgrade() {
+                blueprint.borrow_mut().history.begin(vm.client);
+            }
+            (frame.pos, frame.size)
+        };
         Some(Box::new(DragFrame {
             horizontal: self.horizontal,
             vertical: self.vertical,
             frame: (*self).frame,
             pos: w,
+            start_pos: start_pos,
+            start_size: start_size,
         }))
     }
 }
 
 pub struct Frame {
+    id: Id,
     blueprint: Weak<RefCell<Blueprint>>,
-    typ: &'static Type,
+    typ: Arc<FrameType>,
+    /// Blueprint instantiated by frames of `blueprint_type`.
+    child: Option<Weak<RefCell<Blueprint>>>,
     pos: WorldPoint,
     size: WorldSize,
     global: bool,
@@ -528,8 +603,12 @@ pub struct Frame {
 
 impl Serialize for Frame {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let mut s = serializer.serialize_struct("Frame", 4)?;
-        s.serialize_field("type", &self.typ.name)?;
+        let mut s = serializer.serialize_struct("Frame", 6)?;
+        s.serialize_field("id", &self.id)?;
+        s.serialize_field("type", self.typ.name())?;
+        if let Some(child) = self.child() {
+            s.serialize_field("blueprint", &child.borrow().id)?;
+        }
         s.serialize_field("pos", &SerializablePoint2D(&self.pos))?;
         s.serialize_field("size", &SerializableSize2D(&self.size))?;
         s.serialize_field("global", &self.global)?;
@@ -542,18 +621,55 @@ const PARAM_SPACING: f64 = 2.;
 
 impl Frame {
     fn new(
-        typ: &'static Type,
+        typ: Arc<FrameType>,
+        blueprint: &Arc<RefCell<Blueprint>>,
+        global: bool,
+    ) -> Arc<RefCell<Frame>> {
+        Frame::with_id(new_id(), typ, blueprint, global)
+    }
+    fn with_id(
+        id: Id,
+        typ: Arc<FrameType>,
+        blueprint: &Arc<RefCell<Blueprint>>,
+        global: bool,
+    ) -> Arc<RefCell<Frame>> {
+        Frame::build(id, typ, None, blueprint, global)
+    }
+    /// Frame that runs `child` as a part of `blueprint`.
+    fn instance(
+        types: &TypeRegistry,
+        id: Id,
+        child: &Arc<RefCell<Blueprint>>,
         blueprint: &Arc<RefCell<Blueprint>>,
         global: bool,
     ) -> Arc<RefCell<Frame>> {
+        Frame::build(
+            id,
+            types.get(blueprint_type.name).unwrap(),
+            Some(Arc::downgrade(child)),
+            blueprint,
+            global,
+        )
+    }
+    fn build(
+        id: Id,
+        typ: Arc<FrameType>,
+        child: Option<Weak<RefCell<Blueprint>>>,
+        blueprint: &Arc<RefCell<Blueprint>>,
+        global: bool,
+    ) -> Arc<RefCell<Frame>> {
+        reserve_id(id);
         let f = Arc::new(RefCell::new(Frame {
+            id: id,
             blueprint: Arc::downgrade(blueprint),
-            typ: typ,
+            typ: typ.clone(),
+            child: child,
             pos: WorldPoint::zero(),
             size: WorldSize::new(10., 10.),
             global: global,
         }));
         blueprint.borrow_mut().frames.push(f.clone());
+        blueprint.borrow_mut().frames_by_id.insert(id, f.clone());
         for machine_cell in blueprint.borrow().machines.iter() {
             let mut machine = machine_cell.borrow_mut();
             let mut object = Object {
@@ -562,7 +678,7 @@ impl Frame {
                 execute: false,
                 data: Box::new(()),
             };
-            (typ.init)(&mut object);
+            typ.init(&mut object);
             machine.push(object);
             if global {
                 break;
@@ -570,6 +686,21 @@ impl Frame {
         }
         return f;
     }
+    fn child(&self) -> Option<Arc<RefCell<Blueprint>>> {
+        self.child.as_ref().and_then(Weak::upgrade)
+    }
+    fn parameters(&self) -> Cow<[Parameter]> {
+        match self.child() {
+            Some(child) => Cow::Owned(child.borrow().parameters()),
+            None => Cow::Borrowed(self.typ.parameters()),
+        }
+    }
+    fn title(&self) -> String {
+        match self.child() {
+            Some(child) => child.borrow().name.clone(),
+            None => self.typ.name().to_string(),
+        }
+    }
     fn hit_test(&self, p: &WorldPoint) -> bool {
         let q = *p - self.pos;
         let s = self.size * 0.5;
@@ -596,15 +727,8 @@ impl Frame {
 
 impl Serialize for FrameParam {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        use std::ops::Deref;
         let mut s = serializer.serialize_struct("FrameParam", 2)?;
-        let frame = self.frame.borrow();
-        let blueprint = frame.blueprint.upgrade().unwrap();
-        let blueprint = blueprint.borrow();
-        s.serialize_field(
-            "frame",
-            &blueprint.frame_index(&self.frame),
-        )?;
+        s.serialize_field("frame", &self.frame.borrow().id)?;
         s.serialize_field("param_index", &self.param_index)?;
         s.end()
     }
@@ -637,7 +761,7 @@ impl Serialize for LinkTerminator {
                     "Frame",
                     1,
                 )?;
-                s.serialize_field(&SerializeFrameIndex(frame_rc))?;
+                s.serialize_field(&frame_rc.borrow().id)?;
                 s.end()
             }
             &LinkTerminator::FrameParam(ref frame_param) => {
@@ -671,15 +795,145 @@ impl LinkTerminator {
 }
 
 pub struct Link {
+    id: Id,
     blueprint: Weak<RefCell<Blueprint>>,
     a: LinkTerminator,
     b: LinkTerminator,
     order: i32,
 }
 
+impl Link {
+    /// Whether either end of the link is attached to `frame`.
+    fn touches(&self, frame: &Arc<RefCell<Frame>>) -> bool {
+        fn side_touches(f: &Arc<RefCell<Frame>>, t: &LinkTerminator) -> bool {
+            match t {
+                &LinkTerminator::Frame(ref other_frame) => Arc::ptr_eq(f, other_frame),
+                &LinkTerminator::FrameParam(ref frame_param) => Arc::ptr_eq(f, &frame_param.frame),
+                _ => false,
+            }
+        }
+        side_touches(frame, &self.a) || side_touches(frame, &self.b)
+    }
+
+    fn starts_at(&self, param: &FrameParam) -> bool {
+        match self.a {
+            LinkTerminator::FrameParam(ref start) => {
+                Arc::ptr_eq(&start.frame, &param.frame) && start.param_index == param.param_index
+            }
+            _ => false,
+        }
+    }
+
+    /// Where the order number is drawn - halfway along the arrow.
+    fn label_pos(&self) -> WorldPoint {
+        let start = self.a.get_pos(&self.b);
+        let end = self.b.get_pos(&self.a);
+        start + (end - start) * 0.5
+    }
+}
+
+/// Why objects of `frame` can't be linked to `param`, if they can't.
+fn link_error(param: &FrameParam, frame: &Arc<RefCell<Frame>>) -> Option<String> {
+    let parameter = match param.frame.borrow().parameters().get(param.param_index) {
+        Some(parameter) => parameter.clone(),
+        None => return None,
+    };
+    let frame = frame.borrow();
+    let kind = frame.typ.kind();
+    if parameter.output && !kind.accepts(parameter.kind) {
+        Some(format!(
+            "{} writes {}, which {} can't hold",
+            parameter.name,
+            parameter.kind,
+            frame.title()
+        ))
+    } else if !parameter.output && !parameter.kind.accepts(kind) {
+        Some(format!(
+            "{} expects {}, but {} holds {}",
+            parameter.name,
+            parameter.kind,
+            frame.title(),
+            kind
+        ))
+    } else {
+        None
+    }
+}
+
+/// Index of `link` among the links of its parameter and the number of those links.
+fn link_rank(link_rc: &Arc<RefCell<Link>>) -> Option<(usize, usize)> {
+    let link = link_rc.borrow();
+    let param = match link.a {
+        LinkTerminator::FrameParam(ref param) => param,
+        _ => return None,
+    };
+    let blueprint = match link.blueprint.upgrade() {
+        Some(blueprint) => blueprint,
+        None => return None,
+    };
+    let siblings = blueprint.borrow().param_links(param);
+    siblings
+        .iter()
+        .position(|other| Arc::ptr_eq(other, link_rc))
+        .map(|index| (index, siblings.len()))
+}
+
+const LINK_LABEL_RADIUS: f64 = PARAM_RADIUS * 0.75;
+
+struct ReorderLinkAction {
+    link: Weak<RefCell<Link>>,
+    later: bool,
+}
+
+impl Action for ReorderLinkAction {
+    fn start(
+        self: Box<Self>,
+        vm: &mut Vm,
+        _: DisplayPoint,
+        _: WorldPoint,
+    ) -> Option<Box<TouchReceiver>> {
+        let link_rc = match self.link.upgrade() {
+            Some(link) => link,
+            None => return None,
+        };
+        let (index, count) = match link_rank(&link_rc) {
+            Some(rank) => rank,
+            None => return None,
+        };
+        let other = if self.later { index + 1 } else { index.wrapping_sub(1) };
+        if other >= count {
+            return None;
+        }
+        let blueprint_rc = link_rc.borrow().blueprint.upgrade().unwrap();
+        let mut blueprint = blueprint_rc.borrow_mut();
+        let mut siblings = match link_rc.borrow().a {
+            LinkTerminator::FrameParam(ref param) => blueprint.param_links(param),
+            _ => return None,
+        };
+        siblings.swap(index, other);
+        // Renumbered, because links created before ordering was honoured may share a number.
+        blueprint.history.begin(vm.client);
+        for (order, sibling) in siblings.iter().enumerate() {
+            let before = sibling.borrow().order;
+            let after = order as i32;
+            if before != after {
+                sibling.borrow_mut().order = after;
+                blueprint.history.record(vm.client, Edit::SetOrder {
+                    link: sibling.clone(),
+                    before: before,
+                    after: after,
+                });
+            }
+        }
+        blueprint.history.commit(vm.client);
+        None
+    }
+}
+
 impl Serialize for Link {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let mut s = serializer.serialize_struct("Link", 3)?;
+        let mut s = serializer.serialize_struct("Link", 4)?;
+        s.serialize_field("id", &self.id)?;
         s.serialize_field("a", &self.a)?;
         s.serialize_field("b", &self.b)?;
         s.serialize_field("order", &self.order)?;
@@ -697,16 +951,31 @@ impl Visible for Arc<RefCell<Link>> {
         let length = length2.sqrt();
         let angle = (-v.y).atan2(-v.x);
 
+        // Links that would pass the wrong kind of value are red - also while being dragged.
+        let incompatible = match (&link.a, &link.b) {
+            (&LinkTerminator::FrameParam(ref param), &LinkTerminator::Frame(ref frame)) => {
+                link_error(param, frame).is_some()
+            }
+            (&LinkTerminator::FrameParam(ref param), &LinkTerminator::Point(point)) => {
+                link.blueprint
+                    .upgrade()
+                    .and_then(|blueprint| blueprint.borrow().query_frame(point))
+                    .map_or(false, |frame| link_error(param, &frame).is_some())
+            }
+            _ => false,
+        };
+        let color = if incompatible { "#c0392b" } else { "#000" };
+
         c.save();
         c.translate(start.x, start.y);
         c.rotate(angle);
-        c.fillStyle("#000");
+        c.fillStyle(color);
         c.fillCircle(0., 0., PARAM_RADIUS * 0.5);
 
         const ARROW_WIDTH: f64 = PARAM_RADIUS * 0.5;
         const ARROW_LENGTH: f64 = 5.0;
 
-        c.strokeStyle("#000");
+        c.strokeStyle(color);
         c.beginPath();
         c.moveTo(0., 0.);
         c.lineTo(length - ARROW_LENGTH * 0.5, 0.);
@@ -734,9 +1003,54 @@ impl Visible for Arc<RefCell<Link>> {
         c.fill();
 
         c.restore();
+
+        // Numbered only when the order of arguments matters.
+        if let Some((index, count)) = link_rank(self) {
+            if count > 1 {
+                let label = link.label_pos();
+                c.save();
+                c.fillStyle("white");
+                c.fillCircle(label.x, label.y, LINK_LABEL_RADIUS);
+                c.fillStyle("#000");
+                c.textAlign("center");
+                c.textBaseline("middle");
+                c.fillText(&(index + 1).to_string(), label.x, label.y);
+                c.restore();
+            }
+        }
     }
     fn make_menu(&self, d: DisplayPoint, w: WorldPoint) -> Option<Menu> {
-        None
+        match link_rank(self) {
+            Some((_, count)) if count > 1 => (),
+            _ => return None,
+        }
+        let q = w - self.borrow().label_pos();
+        if q.dot(q).sqrt() >= LINK_LABEL_RADIUS {
+            return None;
+        }
+        Some(Menu {
+            entries: vec![
+                Entry {
+                    name: "Earlier".to_string(),
+                    color: None,
+                    shortcuts: vec!["BracketLeft".to_string()],
+                    action: Box::new(ReorderLinkAction {
+                        link: Arc::downgrade(self),
+                        later: false,
+                    }),
+                },
+                Entry {
+                    name: "Later".to_string(),
+                    color: None,
+                    shortcuts: vec!["BracketRight".to_string()],
+                    action: Box::new(ReorderLinkAction {
+                        link: Arc::downgrade(self),
+                        later: true,
+                    }),
+                },
+            ],
+            color: "#888".to_string(),
+        })
     }
 }
 
@@ -748,32 +1062,18 @@ pub struct Object {
 }
 
 impl Object {
-    pub fn typ(&self) -> &'static Type {
+    pub fn typ(&self) -> Arc<FrameType> {
         let frame = self.frame.borrow();
-        frame.typ
-    }
-}
-
-struct SerializeFrameIndex<'a>(&'a Arc<RefCell<Frame>>);
-
-impl<'a> Serialize for SerializeFrameIndex<'a> {
-    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let frame = self.0.borrow();
-        let blueprint = frame.blueprint.upgrade().unwrap();
-        let blueprint = blueprint.borrow();
-        serializer.serialize_some(&blueprint.frame_index(self.0))
+        frame.typ.clone()
     }
 }
 
 impl Serialize for Object {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         let mut s = serializer.serialize_struct("Object", 3)?;
-        s.serialize_field(
-            "frame",
-            &SerializeFrameIndex(&self.frame),
-        )?;
+        s.serialize_field("frame", &self.frame.borrow().id)?;
         s.serialize_field("execute", &self.execute);
-        let data = (self.frame.borrow().typ.serialize)(self);
+        let data = self.frame.borrow().typ.serialize(self);
         s.serialize_field("data", &data);
         s.end()
     }
@@ -783,29 +1083,44 @@ type ObjectCell = Arc<RefCell<Object>>;
 type RunArg = Vec<ObjectCell>;
 type RunArgs = Vec<RunArg>;
 
+#[derive(Clone)]
 struct Parameter {
-    name: &'static str,
+    name: Cow<'static, str>,
+    /// Frames that produce the linked objects run before the frame with this parameter.
     runnable: bool,
     output: bool,
+    /// Expected kind of the linked objects - or for outputs, the kind of values written to them.
+    kind: Kind,
 }
 
+#[derive(Clone, Copy)]
 pub struct Type {
     name: &'static str,
+    kind: Kind,
+    editable: bool,
     parameters: &'static [Parameter],
     init: &'static (Fn(&mut Object) + Sync),
     run: &'static (Fn(&mut Vm, &ObjectCell, RunArgs) + Sync),
     update: Option<&'static (Fn(&mut Vm, &ObjectCell, Box<Any + Send>) + Sync)>,
+    fail: Option<&'static (Fn(&ObjectCell, String) + Sync)>,
+    menu: Option<&'static (Fn(&Arc<RefCell<Frame>>) -> Vec<Entry> + Sync)>,
+    store: Option<&'static (Fn(&mut Object, &Value, bool) -> Result<(), String> + Sync)>,
     draw: &'static (Fn(&Object, &mut Canvas) + Sync),
     serialize: &'static (Fn(&Object) -> Vec<u8> + Sync),
-    deserialize: &'static (Fn(&mut Object, Vec<u8>) + Sync),
+    deserialize: &'static (Fn(&mut Object, Vec<u8>) -> Result<(), String> + Sync),
 }
 
 static text_type: Type = Type {
     name: "Text",
+    kind: Kind::Text,
+    editable: true,
     parameters: &[],
     init: &|o: &mut Object| { o.data = Box::new("".to_string()); },
     run: &|vm: &mut Vm, o: &ObjectCell, args: RunArgs| {},
     update: None,
+    fail: None,
+    menu: None,
+    store: Some(&store_text),
     draw: &|o: &Object, canvas: &mut Canvas| {
         let font_metrics = canvas.get_font_metrics(6.);
 
@@ -824,19 +1139,26 @@ static text_type: Type = Type {
             .into_bytes()
     },
     deserialize: &|o: &mut Object, data: Vec<u8>| {
-        o.data = Box::new(String::from_utf8(data).ok().unwrap());
+        let text = String::from_utf8(data).map_err(|err| err.to_string())?;
+        o.data = Box::new(text);
+        Ok(())
     },
 };
 
 static empty_type: Type = Type {
     name: "Empty",
+    kind: Kind::Nothing,
+    editable: false,
     parameters: &[],
     init: &|o: &mut Object| {},
     run: &|vm: &mut Vm, o: &ObjectCell, args: RunArgs| {},
     update: None,
+    fail: None,
+    menu: None,
+    store: None,
     draw: &|o: &Object, canvas: &mut Canvas| {},
     serialize: &|o: &Object| -> Vec<u8> { Vec::new() },
-    deserialize: &|o: &mut Object, data: Vec<u8>| {},
+    deserialize: &|o: &mut Object, data: Vec<u8>| Ok(()),
 };
 
 fn new_text(
@@ -848,7 +1170,9 @@ fn new_text(
     height: f64,
 ) {
 
-    let frame_rc = Frame::new(&text_type, &blueprint_rc, true);
+    let vm = blueprint_rc.borrow().vm.upgrade().unwrap();
+    let typ = vm.borrow().types.get(text_type.name).unwrap();
+    let frame_rc = Frame::new(typ, &blueprint_rc, true);
     let mut frame = frame_rc.borrow_mut();
     frame.pos = WorldPoint::new(x, y);
     frame.size = WorldSize::new(width, height);
@@ -858,17 +1182,73 @@ fn new_text(
     object_rc.borrow_mut().data = Box::new(text.to_string());
 }
 
+/// Loads the saved VM and writes its blueprints as SVG files to `dir`, without starting it.
+fn export_svgs(save_config: save::SaveConfig, dir: &str) {
+    let vm = Vm::new_headless();
+    if let Err(err) = Vm::load_file(&vm, &save_config.path) {
+        println!("Couldn't load {}: {}", save_config.path.display(), err);
+        std::process::exit(1);
+    }
+    let result = svg_canvas::export_svgs(&vm.borrow(), std::path::Path::new(dir));
+    match result {
+        Ok(paths) => {
+            for path in paths {
+                println!("Wrote {}", path.display());
+            }
+        }
+        Err(err) => {
+            println!("Couldn't write SVG files to {}: {}", dir, err);
+            std::process::exit(1);
+        }
+    }
+}
+
 fn main() {
+    process::block_quit_signals();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let svg_dir = match args.iter().position(|arg| arg == "--svg") {
+        Some(i) if i + 1 < args.len() => {
+            args.remove(i);
+            Some(args.remove(i))
+        }
+        Some(_) => {
+            println!("--svg requires a directory");
+            std::process::exit(2);
+        }
+        None => None,
+    };
+    let save_config = match save::SaveConfig::from_args(args.into_iter()) {
+        Ok(save_config) => save_config,
+        Err(err) => {
+            println!("{}", err);
+            println!("Usage: os [PATH] [--backups N] [--autosave SECONDS] [--svg DIR]");
+            std::process::exit(2);
+        }
+    };
+    if let Some(dir) = svg_dir {
+        export_svgs(save_config, &dir);
+        return;
+    }
     let mut vm = Vm::new();
+    vm.borrow_mut().save_config = save_config;
     match Vm::load_json(&vm) {
         Result::Ok(_) => (),
-        Result::Err(_) => {
+        Result::Err(ref err) if err.is_missing_file() => {
             let blueprint = Blueprint::new(&vm);
             blueprint.borrow_mut().rename("Default".to_string());
             vm.borrow_mut().activate(&blueprint);
             let machine = Machine::new(&blueprint);
             blueprint.borrow_mut().activate(&machine);
         }
+        Result::Err(err) => {
+            // Refuse to start - saving would overwrite the file that failed to load.
+            println!(
+                "Couldn't load {}: {}",
+                vm.borrow().save_config.path.display(),
+                err
+            );
+            std::process::exit(1);
+        }
     }
 
     vm.borrow_mut().run();
@@ -0,0 +1,245 @@
+extern crate serde_json;
+
+use self::serde_json::Value;
+
+use load::*;
+
+/// Version of the vm.json layout written by the `Serialize` impls.
+pub const FORMAT_VERSION: u64 = 4;
+
+type Migration = fn(&mut Value) -> LoadResult<()>;
+
+/// `MIGRATIONS[i]` upgrades a document from version `i` to version `i + 1`.
+static MIGRATIONS: &'static [Migration] = &[v0_to_v1, v1_to_v2, v2_to_v3, v3_to_v4];
+
+/// Documents saved before versioning was introduced have no `format_version` and count as 0.
+pub fn document_version(json: &Value) -> LoadResult<u64> {
+    match json.get("format_version") {
+        None => Ok(0),
+        Some(version) => {
+            version.as_u64().ok_or_else(|| {
+                LoadError::new(LoadErrorKind::WrongType("a version number"))
+                    .at(Location::Field("format_version"))
+            })
+        }
+    }
+}
+
+/// Upgrades `json` step by step until it matches `FORMAT_VERSION`.
+pub fn migrate(json: &mut Value) -> LoadResult<()> {
+    let mut version = document_version(json)?;
+    if version > FORMAT_VERSION {
+        return Err(LoadError::new(LoadErrorKind::UnsupportedVersion(version)));
+    }
+    while version < FORMAT_VERSION {
+        (MIGRATIONS[version as usize])(json).at(
+            Location::Migration(version),
+        )?;
+        version += 1;
+        root(json)?.insert("format_version".to_string(), Value::from(version));
+    }
+    Ok(())
+}
+
+fn root(json: &mut Value) -> LoadResult<&mut serde_json::Map<String, Value>> {
+    json.as_object_mut().ok_or_else(|| {
+        LoadError::new(LoadErrorKind::WrongType("an object"))
+    })
+}
+
+fn field_mut<'a>(json: &'a mut Value, name: &'static str) -> LoadResult<&'a mut Value> {
+    root(json)?.get_mut(name).ok_or_else(|| {
+        LoadError::new(LoadErrorKind::Missing).at(Location::Field(name))
+    })
+}
+
+fn array_mut<'a>(json: &'a mut Value, name: &'static str) -> LoadResult<&'a mut Vec<Value>> {
+    field_mut(json, name)?.as_array_mut().ok_or_else(|| {
+        LoadError::new(LoadErrorKind::WrongType("an array")).at(Location::Field(name))
+    })
+}
+
+/// Replaces an index into `ids` with the id it points at.
+fn index_to_id(json: &mut Value, ids: &[u64]) -> LoadResult<()> {
+    let index = as_index(json, ids.len())?;
+    *json = Value::from(ids[index]);
+    Ok(())
+}
+
+fn field_index_to_id(json: &mut Value, name: &'static str, ids: &[u64]) -> LoadResult<()> {
+    index_to_id(field_mut(json, name)?, ids).at(Location::Field(name))
+}
+
+/// Version 0 is the unversioned layout - version 1 only adds the `format_version` field.
+fn v0_to_v1(json: &mut Value) -> LoadResult<()> {
+    root(json)?;
+    Ok(())
+}
+
+/// Version 2 gives every blueprint, frame, machine and link an `id` and replaces positional
+/// references with ids. Machines change from a bare list of objects into `{id, objects}`.
+fn v1_to_v2(json: &mut Value) -> LoadResult<()> {
+    let mut next_id = 1;
+    let mut blueprint_ids = Vec::new();
+    let mut frame_ids = Vec::new();
+    let mut machine_ids = Vec::new();
+    for (i, blueprint) in array_mut(json, "blueprints")?.iter_mut().enumerate() {
+        blueprint_ids.push(next_id);
+        let (frames, machines) = v1_to_v2_blueprint(blueprint, &mut next_id)
+            .at(Location::Blueprint(i))
+            .at(Location::Field("blueprints"))?;
+        frame_ids.push(frames);
+        machine_ids.push(machines);
+    }
+    field_index_to_id(json, "active_blueprint", &blueprint_ids)?;
+    for (i, task) in array_mut(json, "tasks")?.iter_mut().enumerate() {
+        let task = task.as_array_mut()
+            .and_then(|task| if task.len() == 3 { Some(task) } else { None })
+            .ok_or_else(|| {
+                LoadError::new(LoadErrorKind::WrongType("a (blueprint, frame, machine) tuple"))
+                    .at(Location::Task(i))
+            })?;
+        let blueprint = as_index(&task[0], blueprint_ids.len()).at(Location::Task(i))?;
+        task[0] = Value::from(blueprint_ids[blueprint]);
+        index_to_id(&mut task[1], &frame_ids[blueprint]).at(Location::Task(i))?;
+        index_to_id(&mut task[2], &machine_ids[blueprint]).at(Location::Task(i))?;
+    }
+    Ok(())
+}
+
+/// Returns the ids assigned to frames and machines of `blueprint`, in their original order.
+fn v1_to_v2_blueprint(blueprint: &mut Value, next_id: &mut u64) -> LoadResult<(Vec<u64>, Vec<u64>)> {
+    let mut assign_id = |json: &mut Value| -> LoadResult<u64> {
+        let id = *next_id;
+        *next_id += 1;
+        root(json)?.insert("id".to_string(), Value::from(id));
+        Ok(id)
+    };
+    assign_id(blueprint)?;
+    let mut frame_ids = Vec::new();
+    for (i, frame) in array_mut(blueprint, "frames")?.iter_mut().enumerate() {
+        frame_ids.push(assign_id(frame).at(Location::Frame(i))?);
+    }
+    for (i, link) in array_mut(blueprint, "links")?.iter_mut().enumerate() {
+        assign_id(link).at(Location::Link(i))?;
+        for side in ["a", "b"].iter() {
+            let terminator = field_mut(link, side).at(Location::Link(i))?;
+            if let Some(frame) = terminator.get_mut("Frame").and_then(|v| v.get_mut(0)) {
+                index_to_id(frame, &frame_ids).at(Location::Link(i))?;
+            }
+            if let Some(frame_param) = terminator.get_mut("FrameParam").and_then(|v| v.get_mut(0)) {
+                field_index_to_id(frame_param, "frame", &frame_ids).at(Location::Link(i))?;
+            }
+        }
+    }
+    let mut machine_ids = Vec::new();
+    for (i, machine) in array_mut(blueprint, "machines")?.iter_mut().enumerate() {
+        let mut objects = machine.take();
+        {
+            let objects = objects.as_array_mut().ok_or_else(|| {
+                LoadError::new(LoadErrorKind::WrongType("an array of objects"))
+                    .at(Location::Machine(i))
+            })?;
+            for (j, object) in objects.iter_mut().enumerate() {
+                field_index_to_id(object, "frame", &frame_ids)
+                    .at(Location::Object(j))
+                    .at(Location::Machine(i))?;
+            }
+        }
+        let mut map = serde_json::Map::new();
+        map.insert("objects".to_string(), objects);
+        *machine = Value::Object(map);
+        machine_ids.push(assign_id(machine)?);
+    }
+    field_index_to_id(blueprint, "active_machine", &machine_ids)?;
+    Ok((frame_ids, machine_ids))
+}
+
+/// Version 3 adds the `entry` frame of every blueprint, started when the blueprint is
+/// instantiated by another one.
+fn v2_to_v3(json: &mut Value) -> LoadResult<()> {
+    for (i, blueprint) in array_mut(json, "blueprints")?.iter_mut().enumerate() {
+        root(blueprint).at(Location::Blueprint(i))?.insert(
+            "entry".to_string(),
+            Value::Null,
+        );
+    }
+    Ok(())
+}
+
+/// Version 4 orders arguments by `order` instead of by the position of links in the blueprint.
+/// Links of every parameter are numbered by their position, so that arguments keep their order.
+fn v3_to_v4(json: &mut Value) -> LoadResult<()> {
+    for (i, blueprint) in array_mut(json, "blueprints")?.iter_mut().enumerate() {
+        let mut counts: Vec<(Value, i64)> = Vec::new();
+        let links = array_mut(blueprint, "links").at(Location::Blueprint(i))?;
+        for (j, link) in links.iter_mut().enumerate() {
+            let param = match link.get("a").and_then(|a| a.get("FrameParam")) {
+                Some(param) => param.clone(),
+                None => continue,
+            };
+            let order = match counts.iter_mut().find(|&&mut (ref other, _)| *other == param) {
+                Some(&mut (_, ref mut count)) => {
+                    *count += 1;
+                    *count
+                }
+                None => {
+                    counts.push((param, 0));
+                    0
+                }
+            };
+            root(link).at(Location::Link(j)).at(Location::Blueprint(i))?.insert(
+                "order".to_string(),
+                Value::from(order),
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Deref;
+    use super::*;
+    use vm::Vm;
+
+    /// The same VM state saved by every historical version of the format.
+    static FIXTURES: &'static [&'static str] = &[
+        include_str!("fixtures/vm_v0.json"),
+        include_str!("fixtures/vm_v1.json"),
+        include_str!("fixtures/vm_v2.json"),
+        include_str!("fixtures/vm_v3.json"),
+        include_str!("fixtures/vm_v4.json"),
+    ];
+
+    #[test]
+    fn fixture_for_every_version() {
+        assert_eq!(FIXTURES.len() as u64, FORMAT_VERSION + 1);
+        for (version, fixture) in FIXTURES.iter().enumerate() {
+            let json: Value = serde_json::from_str(fixture).unwrap();
+            assert_eq!(document_version(&json).unwrap(), version as u64);
+        }
+    }
+
+    #[test]
+    fn migrated_fixtures_load() {
+        let current: Value = serde_json::from_str(FIXTURES[FORMAT_VERSION as usize]).unwrap();
+        for fixture in FIXTURES.iter() {
+            let json: Value = serde_json::from_str(fixture).unwrap();
+            let vm = Vm::new_headless();
+            Vm::load_value(&vm, json).unwrap();
+            let saved = serde_json::to_value(vm.borrow().deref()).unwrap();
+            assert_eq!(saved, current);
+        }
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let mut json: Value = serde_json::from_str(FIXTURES[FORMAT_VERSION as usize]).unwrap();
+        json["format_version"] = Value::from(FORMAT_VERSION + 1);
+        match migrate(&mut json) {
+            Err(LoadError { kind: LoadErrorKind::UnsupportedVersion(_), .. }) => (),
+            _ => panic!("future format_version should be rejected"),
+        }
+    }
+}
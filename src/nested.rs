@@ -0,0 +1,407 @@
+extern crate serde_json;
+
+use std::sync::{Arc, Weak};
+use std::cell::RefCell;
+use std::borrow::Cow;
+
+use blueprint::Blueprint;
+use machine::Machine;
+use canvas::Canvas;
+use menu::*;
+use output::Kind;
+use vm::Vm;
+use history::Edit;
+use scheduler::producers;
+use load::*;
+use id::*;
+use Frame;
+use Object;
+use ObjectCell;
+use Parameter;
+use RunArgs;
+use Type;
+use TouchReceiver;
+use WorldPoint;
+use DisplayPoint;
+use DragFrameAction;
+use DragMode;
+
+/// Data of a frame that instantiates another blueprint.
+///
+/// Every such object runs the child blueprint on its own machine, so separate instances never share
+/// state - not even the objects of global frames.
+struct Instance {
+    machine: Arc<RefCell<Machine>>,
+}
+
+/// Frame placed in a blueprint to turn it into a parameter of the frames that instantiate it.
+///
+/// Its object holds the name of the parameter. Links that point at it receive whatever is linked to
+/// that parameter of the instantiating frame.
+pub static parameter_type: Type = Type {
+    name: "Parameter",
+    kind: Kind::Any,
+    editable: true,
+    parameters: &[],
+    init: &|o: &mut Object| { o.data = Box::new("".to_string()); },
+    run: &|vm: &mut Vm, o: &ObjectCell, args: RunArgs| {},
+    update: None,
+    fail: None,
+    menu: None,
+    store: None,
+    draw: &|o: &Object, canvas: &mut Canvas| {
+        let font_metrics = canvas.get_font_metrics(6.);
+        canvas.fillStyle("#3e64a3");
+        canvas.fillText(
+            o.data.downcast_ref::<String>().unwrap(),
+            2.,
+            2. + font_metrics.ascent as f64,
+        );
+    },
+    serialize: &|o: &Object| -> Vec<u8> {
+        o.data
+            .downcast_ref::<String>()
+            .unwrap()
+            .clone()
+            .into_bytes()
+    },
+    deserialize: &|o: &mut Object, data: Vec<u8>| {
+        let text = String::from_utf8(data).map_err(|err| err.to_string())?;
+        o.data = Box::new(text);
+        Ok(())
+    },
+};
+
+/// Type of frames created by `Frame::instance`. The instantiated blueprint is `Frame::child`.
+pub static blueprint_type: Type = Type {
+    name: "Blueprint",
+    kind: Kind::Nothing,
+    editable: false,
+    parameters: &[],
+    init: &|o: &mut Object| {
+        let child = o.frame.borrow().child().unwrap();
+        o.data = Box::new(Instance { machine: Machine::nested(new_id(), &child) });
+    },
+    run: &|vm: &mut Vm, o: &ObjectCell, args: RunArgs| {
+        let child = o.borrow().frame.borrow().child().unwrap();
+        let machine = match o.borrow().data.downcast_ref::<Instance>() {
+            Some(instance) => instance.machine.clone(),
+            None => return,
+        };
+        let entry = {
+            let child = child.borrow();
+            Machine::sync(&machine, &child);
+            let mut machine = machine.borrow_mut();
+            machine.arguments.clear();
+            for (frame, arg) in child.parameter_frames().iter().zip(args.into_iter()) {
+                machine.arguments.insert(frame.borrow().id, arg);
+            }
+            match child.entry_frame() {
+                Some(entry) => machine.get_object(&entry),
+                None => {
+                    println!("Blueprint {} has no entry frame!", child.name);
+                    let run_id = vm.start_running(o);
+                    vm.finish_running(run_id, false);
+                    return;
+                }
+            }
+        };
+        // The instance is done when its entry is, so that frames using its outputs wait for it.
+        let job = vm.schedule(&entry);
+        vm.start_running_job(o, job);
+    },
+    update: None,
+    fail: None,
+    menu: None,
+    store: None,
+    draw: &|o: &Object, canvas: &mut Canvas| {},
+    serialize: &|o: &Object| -> Vec<u8> {
+        let instance = o.data.downcast_ref::<Instance>().unwrap();
+        let machine = instance.machine.borrow();
+        serde_json::to_vec(&*machine).unwrap()
+    },
+    deserialize: &|o: &mut Object, data: Vec<u8>| {
+        let child = o.frame.borrow().child().ok_or(
+            "instantiated blueprint is missing",
+        )?;
+        let json: serde_json::Value = serde_json::from_slice(&data).map_err(
+            |err| err.to_string(),
+        )?;
+        let id = get_id(&json, "id").map_err(|err| err.to_string())?;
+        let machine = Machine::nested(id, &child);
+        Machine::load_json(&machine, &json).map_err(|err| err.to_string())?;
+        o.data = Box::new(Instance { machine: machine });
+        Ok(())
+    },
+};
+
+impl Blueprint {
+    /// Frames that define the parameters of this blueprint, ordered from top to bottom.
+    pub fn parameter_frames(&self) -> Vec<Arc<RefCell<Frame>>> {
+        let mut frames: Vec<_> = self.frames
+            .iter()
+            .filter(|frame| is_parameter(&frame.borrow()))
+            .cloned()
+            .collect();
+        frames.sort_by(|a, b| {
+            a.borrow().pos.y.partial_cmp(&b.borrow().pos.y).unwrap()
+        });
+        frames
+    }
+
+    /// Parameters of frames that instantiate this blueprint.
+    ///
+    /// Parameters whose frames are written to by outputs inside the blueprint are outputs of the
+    /// instances.
+    pub fn parameters(&self) -> Vec<Parameter> {
+        self.parameter_frames()
+            .iter()
+            .map(|frame| {
+                let name = self.machines
+                    .get(0)
+                    .and_then(|machine| machine.borrow().find_object(frame))
+                    .and_then(|object| object.borrow().data.downcast_ref::<String>().cloned())
+                    .unwrap_or(String::new());
+                let output = !producers(self, frame).is_empty();
+                Parameter {
+                    name: if name.is_empty() {
+                        Cow::Borrowed("Parameter")
+                    } else {
+                        Cow::Owned(name)
+                    },
+                    runnable: !output,
+                    output: output,
+                    kind: Kind::Any,
+                }
+            })
+            .collect()
+    }
+
+    /// Whether `other` is instantiated by this blueprint, directly or through other blueprints.
+    pub fn uses(&self, other: &Arc<RefCell<Blueprint>>) -> bool {
+        self.uses_visited(other, &mut Vec::new())
+    }
+
+    fn uses_visited(&self, other: &Arc<RefCell<Blueprint>>, visited: &mut Vec<Id>) -> bool {
+        self.frames.iter().any(|frame| match frame.borrow().child() {
+            Some(ref child) if Arc::ptr_eq(child, other) => true,
+            Some(child) => {
+                let child = child.borrow();
+                if visited.contains(&child.id) {
+                    return false;
+                }
+                visited.push(child.id);
+                child.uses_visited(other, visited)
+            }
+            None => false,
+        })
+    }
+}
+
+impl Vm {
+    /// Instance in a machine of a blueprint that runs `machine`, possibly through other instances.
+    pub fn top_level_instance(&self, machine: &Arc<RefCell<Machine>>) -> Option<ObjectCell> {
+        for blueprint in self.blueprints.iter() {
+            for top in blueprint.borrow().machines.iter() {
+                let top = top.borrow();
+                if let Some(object) = top.objects.iter().find(|o| runs(o, machine)) {
+                    return Some(object.clone());
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Whether `object` is an instance whose machine is `machine` or contains it.
+fn runs(object: &ObjectCell, machine: &Arc<RefCell<Machine>>) -> bool {
+    match object.borrow().data.downcast_ref::<Instance>() {
+        Some(instance) => {
+            Arc::ptr_eq(&instance.machine, machine) ||
+                instance.machine.borrow().objects.iter().any(|o| runs(o, machine))
+        }
+        None => false,
+    }
+}
+
+pub fn is_parameter(frame: &Frame) -> bool {
+    frame.typ.name() == parameter_type.name
+}
+
+pub struct PlaceBlueprintAction {
+    pub blueprint: Weak<RefCell<Blueprint>>,
+}
+
+impl Action for PlaceBlueprintAction {
+    fn start(
+        self: Box<Self>,
+        vm: &mut Vm,
+        d: DisplayPoint,
+        w: WorldPoint,
+    ) -> Option<Box<TouchReceiver>> {
+        let child = match self.blueprint.upgrade() {
+            Some(child) => child,
+            None => return None,
+        };
+        let blueprint = vm.active_blueprint.upgrade().unwrap();
+        if Arc::ptr_eq(&child, &blueprint) || child.borrow().uses(&blueprint) {
+            println!("Blueprint {} can't contain itself", child.borrow().name);
+            return None;
+        }
+        let frame = Frame::instance(&vm.types, new_id(), &child, &blueprint, true);
+        frame.borrow_mut().pos = w;
+        {
+            let mut blueprint = blueprint.borrow_mut();
+            let snapshot = blueprint.frame_snapshot(&frame).unwrap();
+            blueprint.history.begin(vm.client);
+            blueprint.history.record(vm.client, Edit::InsertFrame(snapshot));
+        }
+
+        Box::new(DragFrameAction::new(&frame, DragMode::Drag, DragMode::Drag)).start(vm, d, w)
+    }
+}
+
+pub struct SetEntryAction {
+    pub frame: Weak<RefCell<Frame>>,
+}
+
+impl Action for SetEntryAction {
+    fn start(
+        self: Box<Self>,
+        _: &mut Vm,
+        _: DisplayPoint,
+        _: WorldPoint,
+    ) -> Option<Box<TouchReceiver>> {
+        if let Some(frame) = self.frame.upgrade() {
+            let blueprint = frame.borrow().blueprint.upgrade();
+            if let Some(blueprint) = blueprint {
+                blueprint.borrow_mut().entry = self.frame;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Deref;
+    use super::*;
+    use fixtures;
+    use fixtures::param;
+    use types::FrameType;
+    use LinkTerminator;
+
+    fn link(blueprint: &Arc<RefCell<Blueprint>>, a: LinkTerminator, b: &Arc<RefCell<Frame>>) {
+        fixtures::link(blueprint, a, LinkTerminator::Frame(b.clone()), 0);
+    }
+
+    /// Parent blueprint with an instance of a child blueprint, whose entry process takes its
+    /// command and arguments from parameters and writes its output to a third one. Returns the VM,
+    /// the instance and the parent's texts linked to the Command and Output of the instance.
+    fn nested_vm() -> (
+        Arc<RefCell<Vm>>,
+        Arc<RefCell<Frame>>,
+        Arc<RefCell<Frame>>,
+        Arc<RefCell<Frame>>,
+    ) {
+        let (vm, child) = fixtures::blueprint("Child");
+        let process = fixtures::frame(&child, "Process", true);
+        child.borrow_mut().entry = Arc::downgrade(&process);
+        let parameters = [("Command", 0), ("Arguments", 1), ("Output", 3)];
+        for (i, &(name, param_index)) in parameters.iter().enumerate() {
+            let frame = fixtures::frame(&child, "Parameter", true);
+            frame.borrow_mut().pos = WorldPoint::new(0., i as f64 * 10.);
+            link(&child, param(&process, param_index), &frame);
+            child.borrow().get_object(&frame).borrow_mut().data = Box::new(name.to_string());
+        }
+
+        let parent = vm.borrow_mut().add_blueprint("Parent".to_string());
+        vm.borrow_mut().activate(&parent);
+        let instance = Frame::instance(&vm.borrow().types, new_id(), &child, &parent, true);
+        let command = fixtures::text(&parent, "echo");
+        let output = fixtures::text(&parent, "");
+        link(&parent, param(&instance, 0), &command);
+        link(&parent, param(&instance, 1), &fixtures::text(&parent, "nested"));
+        link(&parent, param(&instance, 2), &output);
+        (vm, instance, command, output)
+    }
+
+    /// Object of the entry frame on the machine of `instance`.
+    fn entry(instance: &Arc<RefCell<Frame>>) -> ObjectCell {
+        let parent = instance.borrow().blueprint.upgrade().unwrap();
+        let object = parent.borrow().get_object(instance);
+        let machine = object.borrow().data.downcast_ref::<Instance>().unwrap().machine.clone();
+        let entry = instance.borrow().child().unwrap().borrow().entry_frame().unwrap();
+        let entry = machine.borrow().get_object(&entry);
+        entry
+    }
+
+    #[test]
+    fn instance_passes_arguments_to_entry() {
+        let (vm, instance, command, _) = nested_vm();
+        let parameters = instance.borrow().parameters().into_owned();
+        let names: Vec<_> = parameters.iter().map(|param| param.name.as_ref()).collect();
+        assert_eq!(names, vec!["Command", "Arguments", "Output"]);
+        let outputs: Vec<_> = parameters.iter().map(|param| param.output).collect();
+        assert_eq!(outputs, vec![false, false, true]);
+
+        let parent = instance.borrow().blueprint.upgrade().unwrap();
+        let object = parent.borrow().get_object(&instance);
+        let args = vm.borrow().collect_args(&object);
+        blueprint_type.run(&mut vm.borrow_mut(), &object, args);
+
+        let entry = entry(&instance);
+        assert!(!Arc::ptr_eq(&entry.borrow().machine.upgrade().unwrap(), &parent.borrow().machines[0]));
+        let entry_args = vm.borrow().collect_args(&entry);
+        assert!(Arc::ptr_eq(&entry_args[0][0], &parent.borrow().get_object(&command)));
+        assert!(vm.borrow().current_run(&object).is_some());
+        vm.borrow_mut().run_until(|vm| vm.current_run(&object).is_none());
+    }
+
+    /// Frames that read the outputs of an instance run after its nested blueprint.
+    #[test]
+    fn consumers_wait_for_instances() {
+        let (vm, instance, _, output) = nested_vm();
+        let parent = instance.borrow().blueprint.upgrade().unwrap();
+        let cat = fixtures::frame(&parent, "Process", true);
+        let result = fixtures::text(&parent, "");
+        link(&parent, param(&cat, 0), &fixtures::text(&parent, "cat"));
+        link(&parent, param(&cat, 2), &output);
+        link(&parent, param(&cat, 3), &result);
+
+        let object = parent.borrow().get_object(&cat);
+        let result = parent.borrow().get_object(&result);
+        let mut vm = vm.borrow_mut();
+        vm.schedule(&object);
+        vm.run_until(|vm| vm.jobs.is_empty());
+        assert_eq!(result.borrow().data.downcast_ref::<String>().unwrap(), "nested\n");
+    }
+
+    /// Pending work of a nested machine is saved as a run of the instance that contains it.
+    #[test]
+    fn nested_tasks_are_saved_as_instances() {
+        let (vm, instance, _, _) = nested_vm();
+        vm.borrow_mut().tasks.push_back(Arc::downgrade(&entry(&instance)));
+
+        let saved = serde_json::to_value(vm.borrow().deref()).unwrap();
+        let parent = instance.borrow().blueprint.upgrade().unwrap();
+        let parent = parent.borrow();
+        let task = (parent.id, instance.borrow().id, parent.machines[0].borrow().id);
+        assert_eq!(saved["tasks"], serde_json::to_value(vec![task]).unwrap());
+
+        let reloaded = Vm::new_headless();
+        Vm::load_value(&reloaded, saved).unwrap();
+        let task = reloaded.borrow().tasks[0].upgrade().unwrap();
+        assert_eq!(task.borrow().frame.borrow().id, instance.borrow().id);
+    }
+
+    #[test]
+    fn instances_round_trip() {
+        let (vm, _, _, _) = nested_vm();
+        let saved = serde_json::to_value(vm.borrow().deref()).unwrap();
+        let reloaded = Vm::new_headless();
+        Vm::load_value(&reloaded, saved.clone()).unwrap();
+        let resaved = serde_json::to_value(reloaded.borrow().deref()).unwrap();
+        assert_eq!(saved, resaved);
+    }
+}
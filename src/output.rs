@@ -0,0 +1,183 @@
+use std::sync::{Arc, Weak};
+use std::cell::RefCell;
+use std::fmt;
+
+use Object;
+use ObjectCell;
+use RunArgs;
+
+/// Kind of values held by the objects of a type or expected by a parameter.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kind {
+    /// Objects that can't be used as values, e.g. processes.
+    Nothing,
+    Text,
+    /// Raw bytes - text counts as its UTF-8 encoding.
+    Bytes,
+    /// Checked only when the frame runs.
+    Any,
+}
+
+impl Kind {
+    /// Whether a value of `other` kind can be used where this kind is expected.
+    pub fn accepts(self, other: Kind) -> bool {
+        match (self, other) {
+            (Kind::Any, _) | (_, Kind::Any) => true,
+            (Kind::Bytes, Kind::Text) => true,
+            (a, b) => a == b,
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            &Kind::Nothing => "nothing",
+            &Kind::Text => "text",
+            &Kind::Bytes => "bytes",
+            &Kind::Any => "any",
+        })
+    }
+}
+
+/// Result of running a frame, stored in the objects linked to one of its output parameters.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    pub fn kind(&self) -> Kind {
+        match self {
+            &Value::Text(_) => Kind::Text,
+            &Value::Bytes(_) => Kind::Bytes,
+        }
+    }
+}
+
+/// Stores `value` in a Text object.
+pub fn store_text(o: &mut Object, value: &Value, append: bool) -> Result<(), String> {
+    let text = match value {
+        &Value::Text(ref text) => text.clone(),
+        &Value::Bytes(ref bytes) => {
+            String::from_utf8(bytes.clone()).map_err(|_| "bytes are not valid UTF-8".to_string())?
+        }
+    };
+    let contents = o.data.downcast_mut::<String>().ok_or("object holds no text")?;
+    if !append {
+        contents.clear();
+    }
+    contents.push_str(&text);
+    Ok(())
+}
+
+/// Objects linked to an output parameter when the frame started running.
+///
+/// Captured once, so that a long-running frame keeps writing to the same objects even if its links
+/// are edited meanwhile.
+#[derive(Clone)]
+pub struct Output {
+    name: String,
+    targets: Vec<Weak<RefCell<Object>>>,
+}
+
+impl Output {
+    /// Output that writes nowhere.
+    pub fn none() -> Output {
+        Output {
+            name: String::new(),
+            targets: Vec::new(),
+        }
+    }
+
+    /// Objects of `args` linked to parameter `param_index` of `o`, which must be an output.
+    pub fn new(o: &ObjectCell, args: &RunArgs, param_index: usize) -> Result<Output, String> {
+        let object = o.borrow();
+        let frame = object.frame.borrow();
+        let parameters = frame.parameters();
+        let param = parameters.get(param_index).ok_or_else(|| {
+            format!("{} has no parameter {}", frame.title(), param_index)
+        })?;
+        if !param.output {
+            return Err(format!("{} of {} is not an output", param.name, frame.title()));
+        }
+        Ok(Output {
+            name: param.name.to_string(),
+            targets: args[param_index].iter().map(Arc::downgrade).collect(),
+        })
+    }
+
+    /// Replaces the contents of every linked object.
+    pub fn set(&self, value: &Value) -> Result<(), String> {
+        self.write(value, false)
+    }
+
+    /// Adds `value` at the end of every linked object.
+    pub fn append(&self, value: &Value) -> Result<(), String> {
+        self.write(value, true)
+    }
+
+    /// Every object is written to, even if an earlier one refused the value.
+    fn write(&self, value: &Value, append: bool) -> Result<(), String> {
+        let mut errors = Vec::new();
+        for target in self.targets.iter().filter_map(Weak::upgrade) {
+            let typ = target.borrow().typ();
+            if let Err(err) = typ.store(&mut target.borrow_mut(), value, append) {
+                errors.push(format!(
+                    "{} can't store {} in {}: {}",
+                    self.name,
+                    value.kind(),
+                    typ.name(),
+                    err
+                ));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixtures;
+    use Frame;
+    use LinkTerminator;
+
+    #[test]
+    fn outputs_are_type_checked() {
+        let (vm, blueprint) = fixtures::blueprint("Outputs");
+        let process = fixtures::frame(&blueprint, "Process", true);
+        let text = fixtures::frame(&blueprint, "Text", true);
+        let empty = fixtures::frame(&blueprint, "Empty", true);
+        let link = |param_index: usize, target: &Arc<RefCell<Frame>>| {
+            let a = fixtures::param(&process, param_index);
+            fixtures::link(&blueprint, a, LinkTerminator::Frame(target.clone()), 0);
+        };
+        link(3, &text);
+        link(4, &empty);
+        let process = blueprint.borrow().get_object(&process);
+        let text = blueprint.borrow().get_object(&text);
+        let args = vm.borrow().collect_args(&process);
+        let set = |param_index, value: Value| {
+            Output::new(&process, &args, param_index)?.set(&value)
+        };
+
+        set(3, Value::Text("a".to_string())).unwrap();
+        set(3, Value::Bytes(b"b".to_vec())).unwrap();
+        assert_eq!(text.borrow().data.downcast_ref::<String>().unwrap(), "b");
+        assert!(set(3, Value::Bytes(vec![0xFF])).is_err());
+        assert_eq!(
+            set(4, Value::Text("oops".to_string())),
+            Err("Error can't store text in Empty: Empty can't store values".to_string())
+        );
+        assert_eq!(
+            set(0, Value::Text("ls".to_string())),
+            Err("Command of Process is not an output".to_string())
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use std::sync::Arc;
+use std::cell::RefCell;
+
+use canvas::Canvas;
+use vm::Vm;
+use Frame;
+use PARAM_RADIUS;
+
+const COLORS: [&'static str; 6] = [
+    "#e67e22",
+    "#8e44ad",
+    "#16a085",
+    "#2980b9",
+    "#d4ac0d",
+    "#e84393",
+];
+
+/// Colour of a client's pointer, drags and name.
+pub fn client_color(client: i64) -> &'static str {
+    COLORS[client as usize % COLORS.len()]
+}
+
+fn client_name(client: i64) -> String {
+    format!("Client {}", client)
+}
+
+impl Vm {
+    /// Client whose ongoing touch edits `frame`.
+    pub fn editor(&self, frame: &Arc<RefCell<Frame>>) -> Option<i64> {
+        self.sessions
+            .iter()
+            .filter(|&(_, session)| {
+                let edited = session.mouse_handler.as_ref().and_then(|touch| touch.frame());
+                edited.map_or(false, |edited| Arc::ptr_eq(&edited, frame))
+            })
+            .map(|(&client, _)| client)
+            .min()
+    }
+
+    /// Fails if another client is editing `frame`.
+    ///
+    /// A frame belongs to the first client that grabs it until it lets go, so concurrent edits
+    /// don't depend on how the events of different clients interleave.
+    pub fn check_editable(&self, frame: &Arc<RefCell<Frame>>) -> Result<(), String> {
+        match self.editor(frame) {
+            Some(client) if client != self.client => Err(format!(
+                "{} is being edited by {}",
+                frame.borrow().title(),
+                client_name(client)
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Pointers of the other clients and the frames they're dragging, in world space.
+pub fn draw_remote_touches(vm: &Vm, c: &mut Canvas) {
+    let zoom = vm.sessions[&vm.client].zoom.get();
+    let mut clients: Vec<_> = vm.sessions.keys().cloned().collect();
+    clients.sort();
+    for client in clients {
+        if client == vm.client {
+            continue;
+        }
+        let session = &vm.sessions[&client];
+        let color = client_color(client);
+        c.fillStyle(color);
+        c.strokeStyle(color);
+        c.lineWidth(zoom);
+        let frame = session.mouse_handler.as_ref().and_then(|touch| touch.frame());
+        if let Some(frame) = frame {
+            let frame = frame.borrow();
+            c.beginPath();
+            c.rect(
+                frame.pos.x - frame.size.width / 2.,
+                frame.pos.y - frame.size.height / 2.,
+                frame.size.width,
+                frame.size.height,
+            );
+            c.stroke();
+        }
+        let pointer = session.mouse_world();
+        c.fillCircle(pointer.x, pointer.y, PARAM_RADIUS * 0.5 * zoom);
+        c.save();
+        c.translate(pointer.x, pointer.y);
+        c.scale(zoom);
+        c.textAlign("left");
+        c.textBaseline("top");
+        c.fillText(client_name(client).as_ref(), PARAM_RADIUS, PARAM_RADIUS * 0.5);
+        c.restore();
+    }
+}
+
+/// Connected clients, in the top-right corner.
+pub fn draw_presence(vm: &Vm, c: &mut Canvas) {
+    let corner = vm.overlay_corner();
+    let mut clients: Vec<_> = vm.sessions.keys().cloned().collect();
+    clients.sort();
+    c.textAlign("right");
+    c.textBaseline("middle");
+    for (i, &client) in clients.iter().enumerate() {
+        let mut name = client_name(client);
+        if client == vm.client {
+            name.push_str(" (you)");
+        }
+        c.fillStyle(client_color(client));
+        c.fillText(name.as_ref(), -corner.x, corner.y + PARAM_RADIUS * 3. * i as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use menu::Action;
+    use session::Session;
+    use text_type;
+    use DragFrameAction;
+    use DragMode;
+    use WorldPoint;
+    use DisplayPoint;
+
+    /// The client that grabbed a frame first keeps it until it lets go.
+    #[test]
+    fn first_client_keeps_the_frame() {
+        let vm = Vm::new_headless();
+        let blueprint = vm.borrow_mut().add_blueprint("Pairing".to_string());
+        let frame = Frame::new(Arc::new(text_type), &blueprint, true);
+        let mut vm = vm.borrow_mut();
+        vm.sessions.insert(0, Session::new());
+        vm.sessions.insert(1, Session::new());
+        let d = DisplayPoint::new(0., 0.);
+        let drag = |vm: &mut Vm, client: i64, x: f64| {
+            vm.client = client;
+            let action = Box::new(DragFrameAction::new(&frame, DragMode::Drag, DragMode::Drag));
+            let touch = action.start(vm, d, WorldPoint::new(0., 0.));
+            let touch = touch.and_then(|touch| touch.continue_touch(vm, d, WorldPoint::new(x, 0.)));
+            let grabbed = touch.is_some();
+            vm.sessions.get_mut(&client).unwrap().mouse_handler = touch;
+            grabbed
+        };
+
+        assert!(drag(&mut vm, 1, 10.));
+        assert!(!drag(&mut vm, 0, 20.));
+        assert_eq!(vm.editor(&frame), Some(1));
+        assert_eq!(frame.borrow().pos.x, 10.);
+        assert_eq!(
+            vm.check_editable(&frame),
+            Err("Text is being edited by Client 1".to_string())
+        );
+
+        let touch = vm.sessions.get_mut(&1).unwrap().mouse_handler.take().unwrap();
+        touch.end_touch(&mut vm);
+        assert!(drag(&mut vm, 0, 20.));
+        assert_eq!(frame.borrow().pos.x, 30.);
+    }
+}
@@ -1,118 +1,985 @@
-use std::sync::{Mutex, Arc};
+extern crate serde_json;
+extern crate libc;
+
+use std::sync::{Mutex, Arc, Weak};
+use std::sync::mpsc;
+use std::cell::RefCell;
+use std::io::Read;
+use std::str;
 use std::process;
 use std::thread;
 use std::any::Any;
+use std::borrow::Cow;
+use std::os::unix::process::CommandExt;
+use std::time::Duration;
+use std::mem;
+use std::ptr;
+use std::io;
 use Vm;
 use Type;
+use Frame;
+use TouchReceiver;
+use WorldPoint;
+use DisplayPoint;
+use RunAction;
+use menu::*;
 use Parameter;
 use Object;
 use ObjectCell;
 use RunArgs;
 use Canvas;
 use event::Event;
+use output::*;
+
+/// What happened to the last process started by an object.
+#[derive(Clone, PartialEq, Debug)]
+enum ProcessState {
+    Idle,
+    Running,
+    /// Exit code, or `None` if the process was killed by a signal.
+    Exited(Option<i32>),
+    /// Killed after running longer than its Timeout.
+    TimedOut,
+    /// The process couldn't be started.
+    Failed(String),
+}
+
+/// What an object actually executed - its arguments may have changed since.
+#[derive(Clone, PartialEq, Debug)]
+struct Execution {
+    command: String,
+    arguments: Vec<String>,
+    working_directory: Option<String>,
+    environment: Vec<(String, String)>,
+}
+
+impl Execution {
+    fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::Map::new();
+        json.insert("command".to_string(), serde_json::Value::from(self.command.clone()));
+        json.insert("arguments".to_string(), serde_json::Value::from(self.arguments.clone()));
+        json.insert(
+            "working_directory".to_string(),
+            serde_json::Value::from(self.working_directory.clone()),
+        );
+        let environment = self.environment
+            .iter()
+            .map(|&(ref key, ref value)| serde_json::Value::from(vec![key.clone(), value.clone()]))
+            .collect::<Vec<_>>();
+        json.insert("environment".to_string(), serde_json::Value::from(environment));
+        serde_json::Value::Object(json)
+    }
+
+    fn from_json(json: &serde_json::Value) -> Result<Execution, String> {
+        let string = |value: &serde_json::Value| -> Result<String, String> {
+            value.as_str().map(str::to_string).ok_or(
+                format!("{} is not a string", value),
+            )
+        };
+        let list = |field: &str| -> Result<Vec<serde_json::Value>, String> {
+            match json.get(field) {
+                Some(&serde_json::Value::Array(ref values)) => Ok(values.clone()),
+                _ => Err(format!("executed {} is missing", field)),
+            }
+        };
+        let working_directory = match json.get("working_directory") {
+            None |
+            Some(&serde_json::Value::Null) => None,
+            Some(dir) => Some(string(dir)?),
+        };
+        let mut environment = Vec::new();
+        for variable in list("environment")? {
+            match variable.as_array().map(|pair| pair.as_slice()) {
+                Some(&[ref key, ref value]) => environment.push((string(key)?, string(value)?)),
+                _ => return Err(format!("{} is not a KEY, VALUE pair", variable)),
+            }
+        }
+        Ok(Execution {
+            command: string(json.get("command").ok_or("executed command is missing")?)?,
+            arguments: list("arguments")?.iter().map(&string).collect::<Result<_, _>>()?,
+            working_directory: working_directory,
+            environment: environment,
+        })
+    }
+}
+
+/// Parses `KEY=VALUE` lines. Blank lines are skipped.
+fn parse_environment(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut environment = Vec::new();
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match line.find('=') {
+            Some(0) | None => return Err(format!("Environment line \"{}\" is not KEY=VALUE", line)),
+            Some(i) => environment.push((line[..i].to_string(), line[i + 1..].to_string())),
+        }
+    }
+    Ok(environment)
+}
+
+/// Process started by an object. Shared with the thread that waits for it to exit - `None` once it
+/// has exited.
+pub type ChildSlot = Arc<Mutex<Option<process::Child>>>;
 
 struct ProcessData {
-    child: Arc<Mutex<Option<process::Child>>>,
+    child: ChildSlot,
+    /// Identifies the updates of the current run - a restarted process may still report output.
+    run_id: u64,
+    timed_out: bool,
+    state: ProcessState,
+    executed: Option<Execution>,
+    stdout: Stream,
+    stderr: Stream,
+}
+
+impl ProcessData {
+    fn new() -> ProcessData {
+        ProcessData {
+            child: Arc::new(Mutex::new(None)),
+            run_id: 0,
+            timed_out: false,
+            state: ProcessState::Idle,
+            executed: None,
+            stdout: Stream::new(),
+            stderr: Stream::new(),
+        }
+    }
+
+    fn stop(&self) {
+        if let Some(ref mut child) = *self.child.lock().unwrap() {
+            kill_group(child);
+        }
+    }
+}
+
+/// Kills `child` together with all processes it started.
+fn kill_group(child: &mut process::Child) {
+    // A process that was already reaped may have its id reused by an unrelated process.
+    if let Ok(None) = child.try_wait() {
+        unsafe {
+            libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+        }
+    }
+}
+
+/// Kills all processes started by the VM that are still running.
+pub fn kill_all(children: &mut Vec<Weak<Mutex<Option<process::Child>>>>) {
+    for child in children.drain(..).filter_map(|child| child.upgrade()) {
+        if let Some(ref mut child) = *child.lock().unwrap() {
+            kill_group(child);
+        }
+    }
+}
+
+/// SIGINT, SIGTERM & SIGHUP. They're handled by `quit_on_signals` so that the VM can kill its
+/// processes - they run in separate process groups and wouldn't get the SIGINT from a terminal.
+fn quit_signals() -> libc::sigset_t {
+    unsafe {
+        let mut set = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        set
+    }
+}
+
+/// Must be called before any thread is started, so that all threads inherit the signal mask.
+pub fn block_quit_signals() {
+    unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, &quit_signals(), ptr::null_mut());
+    }
+}
+
+/// Sends `Event::Quit` when one of the `quit_signals` arrives.
+pub fn quit_on_signals(tx: mpsc::Sender<Event>) {
+    thread::spawn(move || {
+        let mut signal = 0;
+        unsafe {
+            libc::sigwait(&quit_signals(), &mut signal);
+        }
+        println!("Received signal {}", signal);
+        let (over_tx, over_rx) = mpsc::channel();
+        tx.send(Event::Quit(over_tx)).unwrap();
+        over_rx.recv().ok();
+    });
+}
+
+/// Output pipe of a process, copied into the objects linked to an output parameter.
+///
+/// Reads return the text to append instead of writing it - the process object is still borrowed
+/// then, and it might be linked to its own output.
+struct Stream {
+    output: Output,
+    /// Start of a UTF-8 sequence split between two reads.
+    pending: Vec<u8>,
+}
+
+/// Text to append to an output.
+type Write = (Output, String);
+
+impl Stream {
+    fn new() -> Stream {
+        Stream {
+            output: Output::none(),
+            pending: Vec::new(),
+        }
+    }
+
+    fn connect(&mut self, output: Output) {
+        self.pending.clear();
+        self.output = output;
+    }
+
+    fn read(&mut self, bytes: &[u8]) -> Write {
+        self.pending.extend_from_slice(bytes);
+        (self.output.clone(), take_utf8(&mut self.pending))
+    }
+
+    /// Returns an incomplete UTF-8 sequence left at the end of the stream.
+    fn flush(&mut self) -> Write {
+        let rest = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        (self.output.clone(), rest)
+    }
+}
+
+fn write((output, text): Write) {
+    if text.is_empty() {
+        return;
+    }
+    if let Err(err) = output.append(&Value::Text(text)) {
+        println!("{}", err);
+    }
+}
+
+/// Starts copying the pipes into the objects linked to Output & Error, clearing them.
+fn connect(o: &ObjectCell, args: &RunArgs) -> Result<(), String> {
+    let stdout = Output::new(o, args, 3)?;
+    let stderr = Output::new(o, args, 4)?;
+    if let Some(data) = o.borrow_mut().data.downcast_mut::<ProcessData>() {
+        data.stdout.connect(stdout.clone());
+        data.stderr.connect(stderr.clone());
+    }
+    let empty = Value::Text(String::new());
+    let cleared = stdout.set(&empty);
+    stderr.set(&empty).and(cleared)
+}
+
+/// Bytes that an object feeds into a pipe.
+fn object_bytes(object: &Object) -> Option<Vec<u8>> {
+    if let Some(text) = object.data.downcast_ref::<String>() {
+        Some(text.clone().into_bytes())
+    } else if let Some(bytes) = object.data.downcast_ref::<Vec<u8>>() {
+        Some(bytes.clone())
+    } else {
+        None
+    }
+}
+
+/// Decodes as much of `pending` as possible, leaving only an incomplete trailing sequence.
+///
+/// Invalid bytes are replaced with U+FFFD, like in `String::from_utf8_lossy`.
+fn take_utf8(pending: &mut Vec<u8>) -> String {
+    let mut text = String::new();
+    let mut start = 0;
+    loop {
+        match str::from_utf8(&pending[start..]) {
+            Ok(valid) => {
+                text.push_str(valid);
+                start = pending.len();
+                break;
+            }
+            Err(err) => {
+                let valid_end = start + err.valid_up_to();
+                text.push_str(str::from_utf8(&pending[start..valid_end]).unwrap());
+                match err.error_len() {
+                    Some(invalid) => {
+                        text.push('\u{FFFD}');
+                        start = valid_end + invalid;
+                    }
+                    None => {
+                        start = valid_end;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    pending.drain(..start);
+    text
 }
 
 enum ProcessUpdate {
-    Finished,
+    Exited(Option<i32>),
+    TimedOut,
     Read([u8; 1024], usize),
+    ReadError([u8; 1024], usize),
+}
+
+/// Update of the run with the given id.
+struct Update(u64, ProcessUpdate);
+
+/// Sends everything read from `pipe` to the VM, wrapped with `update`.
+fn forward<R: Read + Send + 'static>(
+    pipe: Option<R>,
+    tx: mpsc::Sender<Event>,
+    run_id: u64,
+    update: fn([u8; 1024], usize) -> ProcessUpdate,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut pipe = match pipe {
+            Some(pipe) => pipe,
+            None => return,
+        };
+        loop {
+            let mut buffer = [0; 1024];
+            match pipe.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(bytes_read) => {
+                    let update = Update(run_id, update(buffer, bytes_read));
+                    tx.send(Event::RunUpdate(run_id, Box::new(update)));
+                }
+                Err(err) => {
+                    println!("Error: {}", err);
+                    break;
+                }
+            }
+        }
+    })
+}
+
+/// Starts the command linked to `args[0]`.
+fn start(vm: &mut Vm, o: &ObjectCell, args: &RunArgs) -> Result<(), String> {
+    let command_rc = args[0].get(0).ok_or("Missing Command argument!")?;
+    let command = command_rc.borrow();
+    let command = command.data.downcast_ref::<String>().ok_or(
+        "Command is not a string!",
+    )?;
+    let mut command_builder = process::Command::new(command);
+    println!("Executing {}", command);
+    let mut arguments = Vec::new();
+    for arg_rc in args[1].iter() {
+        let arg = arg_rc.borrow();
+        if let Some(arg) = arg.data.downcast_ref::<String>() {
+            command_builder.arg(arg);
+            arguments.push(arg.clone());
+        } else {
+            println!("Argument is not a string!");
+        }
+    }
+    let working_directory = match args[6].get(0) {
+        Some(dir) => {
+            let dir = dir.borrow();
+            let dir = dir.data.downcast_ref::<String>().ok_or(
+                "Working directory is not a string!",
+            )?;
+            // Tolerate the newline printed by commands such as `pwd`.
+            let dir = dir.trim_right_matches('\n').to_string();
+            command_builder.current_dir(&dir);
+            Some(dir)
+        }
+        None => None,
+    };
+    let mut environment = Vec::new();
+    for env_rc in args[7].iter() {
+        let env = env_rc.borrow();
+        let text = env.data.downcast_ref::<String>().ok_or(
+            "Environment is not a string!",
+        )?;
+        environment.extend(parse_environment(text)?);
+    }
+    command_builder.envs(environment.iter().cloned());
+    if let Some(data) = o.borrow_mut().data.downcast_mut::<ProcessData>() {
+        data.executed = Some(Execution {
+            command: command.clone(),
+            arguments: arguments,
+            working_directory: working_directory,
+            environment: environment,
+        });
+    }
+    let mut input = Vec::new();
+    for input_rc in args[2].iter() {
+        match object_bytes(&input_rc.borrow()) {
+            Some(bytes) => input.extend(bytes),
+            None => println!("Input can't be converted to bytes!"),
+        }
+    }
+    let timeout = match args[5].get(0) {
+        Some(timeout) => Some(parse_timeout(&timeout.borrow())?),
+        None => None,
+    };
+    unsafe {
+        command_builder.pre_exec(|| {
+            // Own process group, so that `kill_group` also reaches the processes it starts.
+            libc::setpgid(0, 0);
+            // The signal mask is inherited through exec.
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &quit_signals(), ptr::null_mut());
+            Ok(())
+        });
+    }
+    let mut child = command_builder
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("Couldn't start {}: {}", command, err))?;
+    // Written on a separate thread - the child may fill stdout before it reads all of
+    // stdin. Dropping the handle closes stdin so that the child sees the end of input.
+    let stdin = child.stdin.take();
+    thread::spawn(move || {
+        use std::io::Write;
+        if let Some(mut stdin) = stdin {
+            if let Err(err) = stdin.write_all(&input) {
+                println!("Couldn't write process input: {}", err);
+            }
+        }
+    });
+    let run_id = vm.start_running(o);
+    let tx = vm.tx.clone();
+    let stdout = forward(child.stdout.take(), tx.clone(), run_id, ProcessUpdate::Read);
+    let stderr = forward(child.stderr.take(), tx.clone(), run_id, ProcessUpdate::ReadError);
+    let slot: ChildSlot = Arc::new(Mutex::new(Some(child)));
+    vm.children.retain(|child| child.upgrade().is_some());
+    vm.children.push(Arc::downgrade(&slot));
+    if let Some(data) = o.borrow_mut().data.downcast_mut::<ProcessData>() {
+        data.child = slot.clone();
+        data.run_id = run_id;
+        data.timed_out = false;
+    }
+    if let Some(timeout) = timeout {
+        let slot = slot.clone();
+        let tx = tx.clone();
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            if let Some(ref mut child) = *slot.lock().unwrap() {
+                let update = Update(run_id, ProcessUpdate::TimedOut);
+                tx.send(Event::RunUpdate(run_id, Box::new(update)));
+                kill_group(child);
+            }
+        });
+    }
+    thread::spawn(move || {
+        // Reported after all output, so that the object is complete when it's marked as done.
+        stdout.join();
+        stderr.join();
+        let code = wait(&slot).unwrap_or_else(|err| {
+            println!("Couldn't wait for process: {}", err);
+            None
+        });
+        let update = Update(run_id, ProcessUpdate::Exited(code));
+        tx.send(Event::RunUpdate(run_id, Box::new(update)));
+    });
+    Ok(())
+}
+
+/// Waits for the process in `slot` to exit and empties the slot.
+///
+/// Polls instead of blocking in `Child::wait` so that the slot stays available to `kill_group`.
+fn wait(slot: &ChildSlot) -> io::Result<Option<i32>> {
+    loop {
+        {
+            let mut slot = slot.lock().unwrap();
+            let status = match *slot {
+                Some(ref mut child) => child.try_wait(),
+                None => return Ok(None),
+            };
+            match status {
+                Ok(Some(status)) => {
+                    *slot = None;
+                    return Ok(status.code());
+                }
+                Ok(None) => (),
+                Err(err) => {
+                    *slot = None;
+                    return Err(err);
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Reads the number of seconds from a Timeout object.
+fn parse_timeout(object: &Object) -> Result<Duration, String> {
+    let text = object.data.downcast_ref::<String>().ok_or(
+        "Timeout is not a string!",
+    )?;
+    let seconds: f64 = text.trim().parse().map_err(|_| {
+        format!("Timeout \"{}\" is not a number of seconds", text)
+    })?;
+    if !(seconds >= 0.) {
+        return Err(format!("Timeout \"{}\" is negative", text));
+    }
+    let nanos = (seconds.fract() * 1e9) as u32;
+    Ok(Duration::new(seconds.trunc() as u64, nanos))
+}
+
+/// Stops the process of `frame` on the active machine of its blueprint.
+fn stop(frame: &Weak<RefCell<Frame>>) {
+    let frame = match frame.upgrade() {
+        Some(frame) => frame,
+        None => return,
+    };
+    let blueprint = match frame.borrow().blueprint.upgrade() {
+        Some(blueprint) => blueprint,
+        None => return,
+    };
+    let object = blueprint.borrow().get_object(&frame);
+    let object = object.borrow();
+    if let Some(data) = object.data.downcast_ref::<ProcessData>() {
+        data.stop();
+    }
+}
+
+struct StopAction {
+    frame: Weak<RefCell<Frame>>,
+}
+
+impl Action for StopAction {
+    fn start(
+        self: Box<Self>,
+        _: &mut Vm,
+        _: DisplayPoint,
+        _: WorldPoint,
+    ) -> Option<Box<TouchReceiver>> {
+        stop(&self.frame);
+        None
+    }
+}
+
+struct RestartAction {
+    frame: Weak<RefCell<Frame>>,
+}
+
+impl Action for RestartAction {
+    fn start(
+        self: Box<Self>,
+        vm: &mut Vm,
+        d: DisplayPoint,
+        w: WorldPoint,
+    ) -> Option<Box<TouchReceiver>> {
+        stop(&self.frame);
+        match self.frame.upgrade() {
+            Some(frame) => Box::new(RunAction::new(&frame)).start(vm, d, w),
+            None => None,
+        }
+    }
+}
+
+fn set_state(o: &ObjectCell, state: ProcessState) {
+    if let Some(data) = o.borrow_mut().data.downcast_mut::<ProcessData>() {
+        data.state = state;
+    }
+}
+
+/// Shows why `o` couldn't run, in its state and its Error.
+fn fail(o: &ObjectCell, err: String) {
+    println!("{}", err);
+    let stderr = o.borrow().data.downcast_ref::<ProcessData>().map(|data| {
+        data.stderr.output.clone()
+    });
+    if let Some(stderr) = stderr {
+        write((stderr, err.clone()));
+    }
+    set_state(o, ProcessState::Failed(err));
 }
 
 pub static process_type: Type = Type {
     name: "Process",
+    kind: Kind::Nothing,
+    editable: false,
     parameters: &[
         Parameter {
-            name: "Command",
-            runnable: false,
+            name: Cow::Borrowed("Command"),
+            runnable: true,
             output: false,
+            kind: Kind::Text,
         },
         Parameter {
-            name: "Arguments",
-            runnable: false,
+            name: Cow::Borrowed("Arguments"),
+            runnable: true,
             output: false,
+            kind: Kind::Text,
         },
         Parameter {
-            name: "Input",
-            runnable: false,
+            name: Cow::Borrowed("Input"),
+            runnable: true,
             output: false,
+            kind: Kind::Bytes,
+        },
+        Parameter {
+            name: Cow::Borrowed("Output"),
+            runnable: false,
+            output: true,
+            kind: Kind::Text,
         },
         Parameter {
-            name: "Output",
+            name: Cow::Borrowed("Error"),
             runnable: false,
             output: true,
+            kind: Kind::Text,
+        },
+        Parameter {
+            name: Cow::Borrowed("Timeout"),
+            runnable: true,
+            output: false,
+            kind: Kind::Text,
+        },
+        Parameter {
+            name: Cow::Borrowed("Working directory"),
+            runnable: true,
+            output: false,
+            kind: Kind::Text,
+        },
+        Parameter {
+            name: Cow::Borrowed("Environment"),
+            runnable: true,
+            output: false,
+            kind: Kind::Text,
         },
     ],
     init: &|o: &mut Object| {
-        o.data = Box::new(ProcessData { child: Arc::new(Mutex::new(None)) });
+        o.data = Box::new(ProcessData::new());
     },
-    run: &|vm: &mut Vm, o: &ObjectCell, args: RunArgs| if let Some(command_rc) = args[0].get(0) {
-        let command = command_rc.borrow();
-        if let Some(command) = command.data.downcast_ref::<String>() {
-            let mut command_builder = process::Command::new(command);
-            println!("Executing {}", command);
-            for arg_rc in args[1].iter() {
-                let arg = arg_rc.borrow();
-                if let Some(arg) = arg.data.downcast_ref::<String>() {
-                    command_builder.arg(arg);
-                } else {
-                    println!("Argument is not a string!");
-                }
+    run: &|vm: &mut Vm, o: &ObjectCell, args: RunArgs| {
+        match connect(o, &args).and_then(|_| start(vm, o, &args)) {
+            Ok(()) => set_state(o, ProcessState::Running),
+            Err(err) => {
+                fail(o, err);
+                // Jobs waiting for this object learn about the failure from its run.
+                let run_id = vm.start_running(o);
+                vm.finish_running(run_id, false);
             }
-            let child = command_builder
-                .stdout(process::Stdio::piped())
-                .spawn()
-                .expect("failed to execute ls");
-            let run_id = vm.start_running(o);
-            let tx = vm.tx.clone();
-            let output_rc = args[3].get(0).unwrap().clone();
-            thread::spawn(move || {
-
-                use std::io::Read;
-                let mut stdout = &mut child.stdout.unwrap();
-                loop {
-                    let mut buffer = [0; 1024];
-                    match stdout.read(&mut buffer) {
-                        Ok(bytes_read) => {
-                            if bytes_read == 0 {
-                                tx.send(
-                                    Event::RunUpdate(run_id, Box::new(ProcessUpdate::Finished)),
-                                );
-                                break;
-                            } else {
-                                tx.send(Event::RunUpdate(
-                                    run_id,
-                                    Box::new(ProcessUpdate::Read(buffer, bytes_read)),
-                                ));
-                            }
-                        }
-                        Err(err) => {
-                            println!("Error: {}", err);
-                            break;
-                        }
-                    }
-                }
-                //let output = child.wait_with_output().expect("failed to wait on ls");
-                //println!("Result: {}", String::from_utf8(output.stdout).unwrap());
-            });
-        } else {
-            println!("Command is not a string!");
         }
-    } else {
-        println!("Missing Command argument!");
     },
     update: Some(&|vm: &mut Vm, o: &ObjectCell, data: Box<Any + Send>| {
-        let process_update = data.downcast_ref::<ProcessUpdate>().unwrap();
-        match process_update {
-            &ProcessUpdate::Finished => println!("Received update"),
-            &ProcessUpdate::Read(buffer, bytes_read) => {
-                println!("Read {} bytes", bytes_read);
+        let &Update(run_id, ref process_update) = data.downcast_ref::<Update>().unwrap();
+        let mut writes = Vec::new();
+        {
+            let mut object = o.borrow_mut();
+            let data = match object.data.downcast_mut::<ProcessData>() {
+                Some(data) => data,
+                None => return,
+            };
+            if run_id != data.run_id {
+                // A superseded run still has to end, or jobs waiting for it would never continue.
+                if let &ProcessUpdate::Exited(_) = process_update {
+                    vm.finish_running(run_id, false);
+                }
+                return;
+            }
+            match process_update {
+                &ProcessUpdate::Exited(code) => {
+                    writes.push(data.stdout.flush());
+                    writes.push(data.stderr.flush());
+                    data.state = if data.timed_out {
+                        ProcessState::TimedOut
+                    } else {
+                        ProcessState::Exited(code)
+                    };
+                    vm.finish_running(run_id, data.state == ProcessState::Exited(Some(0)));
+                }
+                &ProcessUpdate::TimedOut => data.timed_out = true,
+                &ProcessUpdate::Read(buffer, bytes_read) => {
+                    writes.push(data.stdout.read(&buffer[..bytes_read]))
+                }
+                &ProcessUpdate::ReadError(buffer, bytes_read) => {
+                    writes.push(data.stderr.read(&buffer[..bytes_read]))
+                }
             }
         }
-
+        for output in writes {
+            write(output);
+        }
     }),
-    draw: &|o: &Object, canvas: &mut Canvas| {},
-    serialize: &|o: &Object| -> Vec<u8> { Vec::new() },
-    deserialize: &|o: &mut Object, data: Vec<u8>| { (process_type.init)(o); },
+    fail: Some(&|o: &ObjectCell, err: String| fail(o, err)),
+    menu: Some(&|frame: &Arc<RefCell<Frame>>| {
+        vec![
+            Entry {
+                name: "Stop".to_string(),
+                color: None,
+                shortcuts: vec!["Escape".to_string()],
+                action: Box::new(StopAction { frame: Arc::downgrade(frame) }),
+            },
+            Entry {
+                name: "Restart".to_string(),
+                color: None,
+                shortcuts: vec!["Shift+Space".to_string()],
+                action: Box::new(RestartAction { frame: Arc::downgrade(frame) }),
+            },
+        ]
+    }),
+    store: None,
+    draw: &|o: &Object, canvas: &mut Canvas| {
+        let state = match o.data.downcast_ref::<ProcessData>() {
+            Some(data) => data.state.clone(),
+            None => return,
+        };
+        let (color, text) = match state {
+            ProcessState::Idle => return,
+            ProcessState::Running => ("#3e64a3", "running".to_string()),
+            ProcessState::Exited(Some(0)) => ("#2e8b57", "succeeded".to_string()),
+            ProcessState::Exited(Some(code)) => ("#c0392b", format!("failed (exit {})", code)),
+            ProcessState::Exited(None) => ("#c0392b", "killed".to_string()),
+            ProcessState::TimedOut => ("#c0392b", "timed out".to_string()),
+            ProcessState::Failed(err) => ("#c0392b", format!("failed: {}", err)),
+        };
+        let font_metrics = canvas.get_font_metrics(6.);
+        canvas.fillStyle(color);
+        canvas.fillText(text.as_ref(), 2., 2. + font_metrics.ascent as f64);
+    },
+    serialize: &|o: &Object| -> Vec<u8> {
+        let data = match o.data.downcast_ref::<ProcessData>() {
+            Some(data) => data,
+            None => return Vec::new(),
+        };
+        let mut json = serde_json::Map::new();
+        if let Some(ref executed) = data.executed {
+            json.insert("executed".to_string(), executed.to_json());
+        }
+        match data.state.clone() {
+            // Processes don't survive a restart of the VM.
+            ProcessState::Idle | ProcessState::Running if json.is_empty() => return Vec::new(),
+            ProcessState::Idle | ProcessState::Running => (),
+            ProcessState::Exited(code) => {
+                json.insert("exit_code".to_string(), serde_json::Value::from(code));
+            }
+            ProcessState::TimedOut => {
+                json.insert("timed_out".to_string(), serde_json::Value::from(true));
+            }
+            ProcessState::Failed(err) => {
+                json.insert("error".to_string(), serde_json::Value::from(err));
+            }
+        }
+        serde_json::to_vec(&json).unwrap()
+    },
+    deserialize: &|o: &mut Object, data: Vec<u8>| {
+        let mut process = ProcessData::new();
+        if !data.is_empty() {
+            let json: serde_json::Value = serde_json::from_slice(&data).map_err(
+                |err| err.to_string(),
+            )?;
+            process.state = if let Some(err) = json.get("error").and_then(|err| err.as_str()) {
+                ProcessState::Failed(err.to_string())
+            } else if json.get("timed_out").is_some() {
+                ProcessState::TimedOut
+            } else if let Some(code) = json.get("exit_code") {
+                ProcessState::Exited(code.as_i64().map(|code| code as i32))
+            } else if json.get("executed").is_some() {
+                ProcessState::Idle
+            } else {
+                return Err("unknown process state".to_string());
+            };
+            if let Some(executed) = json.get("executed") {
+                process.executed = Some(Execution::from_json(executed)?);
+            }
+        }
+        o.data = Box::new(process);
+        Ok(())
+    },
 };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blueprint::Blueprint;
+    use machine::Machine;
+    use fixtures;
+    use Frame;
+    use LinkTerminator;
+    use text_type;
+    use types::FrameType;
+
+    #[test]
+    fn split_characters_are_joined() {
+        let mut pending = "zażółć".as_bytes().to_vec();
+        let tail = pending.split_off(3);
+        assert_eq!(take_utf8(&mut pending), "za");
+        assert_eq!(pending, vec![0xC5]);
+        pending.extend_from_slice(&tail);
+        assert_eq!(take_utf8(&mut pending), "żółć");
+        assert!(pending.is_empty());
+
+        let mut invalid = vec![b'a', 0xFF, b'b', 0xE2, 0x82];
+        assert_eq!(take_utf8(&mut invalid), "a\u{FFFD}b");
+        assert_eq!(invalid, vec![0xE2, 0x82]);
+    }
+
+    #[test]
+    fn stdout_is_appended_to_output() {
+        let vm = Vm::new_headless();
+        let blueprint = Blueprint::new(&vm);
+        Machine::new(&blueprint);
+        let process = Frame::new(Arc::new(process_type), &blueprint, true);
+        let output = Frame::new(Arc::new(text_type), &blueprint, true);
+        let process = blueprint.borrow().get_object(&process);
+        let output = blueprint.borrow().get_object(&output);
+        let mut args = vec![vec![]; process_type.parameters.len()];
+        args[3].push(output.clone());
+        connect(&process, &args).unwrap();
+
+        let text = "1024 bytes later: €".as_bytes();
+        let split = text.len() - 1;
+        for chunk in [&text[..split], &text[split..]].iter() {
+            let mut buffer = [0; 1024];
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            let update = Box::new(Update(0, ProcessUpdate::Read(buffer, chunk.len())));
+            process_type.update(&mut vm.borrow_mut(), &process, update);
+        }
+        let exited = Box::new(Update(0, ProcessUpdate::Exited(Some(0))));
+        process_type.update(&mut vm.borrow_mut(), &process, exited);
+        assert_eq!(
+            output.borrow().data.downcast_ref::<String>().unwrap(),
+            "1024 bytes later: €"
+        );
+    }
+
+    fn state(object: &ObjectCell) -> ProcessState {
+        object.borrow().data.downcast_ref::<ProcessData>().unwrap().state.clone()
+    }
+
+    /// Runs `command` with `args` and texts given to other parameters by index, returning the
+    /// process object with its Output & Error.
+    fn run(
+        command: &str,
+        args: &[&str],
+        extra: &[(usize, &str)],
+    ) -> (Arc<RefCell<Vm>>, ObjectCell, ObjectCell, ObjectCell) {
+        let vm = Vm::new_headless();
+        let blueprint = vm.borrow_mut().add_blueprint("Test".to_string());
+        vm.borrow_mut().activate(&blueprint);
+        let process = Frame::new(Arc::new(process_type), &blueprint, true);
+        let text = |contents: &str| {
+            let frame = Frame::new(Arc::new(text_type), &blueprint, true);
+            let object = blueprint.borrow().get_object(&frame);
+            object.borrow_mut().data = Box::new(contents.to_string());
+            object
+        };
+        let process = blueprint.borrow().get_object(&process);
+        let output = text("");
+        let error = text("");
+        let mut run_args = vec![vec![]; process_type.parameters.len()];
+        run_args[0].push(text(command));
+        run_args[1].extend(args.iter().map(|arg| text(arg)));
+        run_args[3].push(output.clone());
+        run_args[4].push(error.clone());
+        for &(i, contents) in extra.iter() {
+            run_args[i].push(text(contents));
+        }
+        process_type.run(&mut vm.borrow_mut(), &process, run_args);
+        (vm, process, output, error)
+    }
+
+    #[test]
+    fn exit_status_and_stderr() {
+        let (vm, process, _, error) = run("sh", &["-c", "echo oops >&2; exit 3"], &[]);
+        assert_eq!(state(&process), ProcessState::Running);
+        vm.borrow_mut().run_until(|_| state(&process) != ProcessState::Running);
+        assert_eq!(state(&process), ProcessState::Exited(Some(3)));
+        assert_eq!(error.borrow().data.downcast_ref::<String>().unwrap(), "oops\n");
+    }
+
+    #[test]
+    fn spawn_failure_is_reported() {
+        let (_, process, _, error) = run("/nonexistent/command", &[], &[]);
+        match state(&process) {
+            ProcessState::Failed(_) => (),
+            state => panic!("unexpected state {:?}", state),
+        }
+        assert!(error.borrow().data.downcast_ref::<String>().unwrap().contains("/nonexistent"));
+    }
+
+    #[test]
+    fn stop_kills_process_group() {
+        // The shell forks `sleep`, which would keep the output pipes open if it survived.
+        let (vm, process, _, _) = run("sh", &["-c", "sleep 30; true"], &[]);
+        process.borrow().data.downcast_ref::<ProcessData>().unwrap().stop();
+        vm.borrow_mut().run_until(|_| state(&process) != ProcessState::Running);
+        assert_eq!(state(&process), ProcessState::Exited(None));
+    }
+
+    /// Running a process that depends on itself fails it with a description of the cycle.
+    #[test]
+    fn cycles_fail_the_process() {
+        let (vm, blueprint) = fixtures::blueprint("Loop");
+        let frame = fixtures::frame(&blueprint, "Process", true);
+        let data = fixtures::text(&blueprint, "");
+        let input = LinkTerminator::Frame(data.clone());
+        fixtures::link(&blueprint, fixtures::param(&frame, 2), input, 0);
+        fixtures::link(&blueprint, fixtures::param(&frame, 3), LinkTerminator::Frame(data), 0);
+        let process = blueprint.borrow().get_object(&frame);
+        vm.borrow_mut().schedule(&process);
+        assert_eq!(
+            state(&process),
+            ProcessState::Failed("Cycle in blueprint Loop: Process <- Process".to_string())
+        );
+    }
+
+    /// A job waiting for a process continues when the process is restarted.
+    #[test]
+    fn superseded_runs_finish() {
+        let (vm, blueprint) = fixtures::blueprint("Restart");
+        let frame = fixtures::frame(&blueprint, "Process", true);
+        let command = fixtures::text(&blueprint, "sleep");
+        let duration = fixtures::text(&blueprint, "30");
+        fixtures::link(&blueprint, fixtures::param(&frame, 0), LinkTerminator::Frame(command), 0);
+        fixtures::link(&blueprint, fixtures::param(&frame, 1), LinkTerminator::Frame(duration), 0);
+        let process = blueprint.borrow().get_object(&frame);
+        let mut vm = vm.borrow_mut();
+        vm.schedule(&process);
+        assert_eq!(vm.jobs.len(), 1);
+
+        stop(&Arc::downgrade(&frame));
+        vm.run_object(&process);
+        vm.run_until(|vm| vm.jobs.is_empty());
+        assert_eq!(state(&process), ProcessState::Running);
+        process.borrow().data.downcast_ref::<ProcessData>().unwrap().stop();
+    }
+
+    #[test]
+    fn timeout_kills_process() {
+        let (vm, process, _, _) = run("sh", &["-c", "sleep 30; true"], &[(5, "0.2")]);
+        vm.borrow_mut().run_until(|_| state(&process) != ProcessState::Running);
+        assert_eq!(state(&process), ProcessState::TimedOut);
+        assert!(vm.borrow().children.iter().all(|child| {
+            child.upgrade().map_or(true, |child| child.lock().unwrap().is_none())
+        }));
+    }
+
+    #[test]
+    fn working_directory_and_environment() {
+        let (vm, process, output, _) = run(
+            "sh",
+            &["-c", "pwd; echo $GREETING $NAME"],
+            &[(6, "/\n"), (7, "GREETING=hello\n\n"), (7, "NAME=a=b")],
+        );
+        vm.borrow_mut().run_until(|_| state(&process) != ProcessState::Running);
+        assert_eq!(output.borrow().data.downcast_ref::<String>().unwrap(), "/\nhello a=b\n");
+
+        let executed = Execution {
+            command: "sh".to_string(),
+            arguments: vec!["-c".to_string(), "pwd; echo $GREETING $NAME".to_string()],
+            working_directory: Some("/".to_string()),
+            environment: vec![
+                ("GREETING".to_string(), "hello".to_string()),
+                ("NAME".to_string(), "a=b".to_string()),
+            ],
+        };
+        let saved = process_type.serialize(&process.borrow());
+        let mut reloaded = process.borrow_mut();
+        process_type.deserialize(&mut reloaded, saved).unwrap();
+        let data = reloaded.data.downcast_ref::<ProcessData>().unwrap();
+        assert_eq!(data.executed, Some(executed));
+        assert_eq!(data.state, ProcessState::Exited(Some(0)));
+    }
+
+    #[test]
+    fn malformed_environment_is_reported() {
+        let (_, process, _, _) = run("true", &[], &[(7, "GREETING")]);
+        assert_eq!(
+            state(&process),
+            ProcessState::Failed("Environment line \"GREETING\" is not KEY=VALUE".to_string())
+        );
+    }
+}
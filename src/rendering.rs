@@ -0,0 +1,110 @@
+use std::time;
+use std::str;
+
+use binary_canvas::write_length;
+
+/// Parts of the view that are drawn and sent to clients separately, bottom to top.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Layer {
+    Frames,
+    Links,
+    /// Pointers and drags of the other clients.
+    Remote,
+    /// Machine circles, blueprint list & connected clients.
+    Overlay,
+    Menus,
+}
+
+pub const LAYERS: [Layer; 5] = [
+    Layer::Frames,
+    Layer::Links,
+    Layer::Remote,
+    Layer::Overlay,
+    Layer::Menus,
+];
+
+/// Encoding of the renderings sent to a client, chosen by the client when it connects.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Protocol {
+    /// Text messages with `JsonCanvas` commands.
+    Json,
+    /// Binary messages with `BinaryCanvas` commands.
+    Binary,
+}
+
+/// Progress of the last rendering sent to a client.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FrameState {
+    /// The client is ready for the next rendering.
+    Idle,
+    /// Sent, but not drawn yet.
+    Sent,
+    /// Drawn - the client will ask for the next one with `RenderingReady`.
+    Drawn,
+}
+
+/// Renderings that took longer than this to be drawn are assumed to be lost.
+pub fn frame_timeout() -> time::Duration {
+    time::Duration::from_secs(1)
+}
+
+/// Replaces the `retained` display lists with `layers`. Returns the ones that changed, with their
+/// indices.
+pub fn diff_layers(retained: &mut Vec<Vec<u8>>, layers: Vec<Vec<u8>>) -> Vec<(usize, Vec<u8>)> {
+    retained.resize(layers.len(), Vec::new());
+    let mut changed = Vec::new();
+    for (i, layer) in layers.into_iter().enumerate() {
+        if retained[i] != layer {
+            retained[i] = layer.clone();
+            changed.push((i, layer));
+        }
+    }
+    changed
+}
+
+/// Text message that updates the `changed` layers of a client. Layers are JSON arrays of commands.
+pub fn layers_message(changed: &[(usize, Vec<u8>)]) -> String {
+    let changed: Vec<String> = changed
+        .iter()
+        .map(|&(i, ref layer)| format!("[{},{}]", i, str::from_utf8(layer).unwrap()))
+        .collect();
+    format!(
+        r#"{{"type":"layers","count":{},"changed":[{}]}}"#,
+        LAYERS.len(),
+        changed.join(",")
+    )
+}
+
+/// Binary message that updates the `changed` layers of a client: the number of layers and of
+/// changed ones, followed by the index, length and commands of each changed layer.
+pub fn binary_layers_message(changed: &[(usize, Vec<u8>)]) -> Vec<u8> {
+    let mut message = Vec::new();
+    write_length(&mut message, LAYERS.len());
+    write_length(&mut message, changed.len());
+    for &(i, ref layer) in changed.iter() {
+        write_length(&mut message, i);
+        write_length(&mut message, layer.len());
+        message.extend_from_slice(layer);
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_changed_layers_are_sent() {
+        let mut retained = Vec::new();
+        let layers = |frames: &str| vec![frames.as_bytes().to_vec(), b"[]".to_vec()];
+        assert_eq!(diff_layers(&mut retained, layers("[1]")).len(), 2);
+        assert_eq!(diff_layers(&mut retained, layers("[1]")), vec![]);
+        let changed = diff_layers(&mut retained, layers("[2]"));
+        assert_eq!(changed, vec![(0, b"[2]".to_vec())]);
+        assert_eq!(
+            layers_message(&changed),
+            r#"{"type":"layers","count":5,"changed":[[0,[2]]]}"#
+        );
+        assert_eq!(binary_layers_message(&changed), b"\x05\x01\x00\x03[2]".to_vec());
+    }
+}
@@ -0,0 +1,86 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub struct SaveConfig {
+    pub path: PathBuf,
+    /// Number of previous saves kept next to `path` as `<path>.1`, `<path>.2`, ...
+    pub backups: usize,
+    /// State is saved this long after the last change...
+    pub delay: Duration,
+    /// ...and unconditionally at this interval, to capture running tasks & process output.
+    pub interval: Duration,
+}
+
+impl SaveConfig {
+    pub fn new() -> SaveConfig {
+        SaveConfig {
+            path: PathBuf::from("vm.json"),
+            backups: 3,
+            delay: Duration::from_secs(2),
+            interval: Duration::from_secs(60),
+        }
+    }
+
+    /// Reads `[PATH] [--backups N] [--autosave SECONDS]` from the command line.
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<SaveConfig, String> {
+        let mut config = SaveConfig::new();
+        while let Some(arg) = args.next() {
+            match arg.as_ref() {
+                "--backups" => {
+                    config.backups = parse_number(args.next(), "--backups")? as usize;
+                }
+                "--autosave" => {
+                    let seconds = parse_number(args.next(), "--autosave")?;
+                    if seconds == 0 {
+                        return Err("--autosave requires at least 1 second".to_string());
+                    }
+                    config.interval = Duration::from_secs(seconds);
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+                _ => config.path = PathBuf::from(arg),
+            }
+        }
+        Ok(config)
+    }
+
+    fn backup_path(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    /// Replaces the file at `path` with `contents` without ever leaving it half-written.
+    ///
+    /// The contents go to a temporary file that is renamed over `path` once it's flushed to disk.
+    /// The previous version is rotated into the backups beforehand.
+    pub fn write(&self, contents: &[u8]) -> io::Result<()> {
+        let mut tmp_name = self.path.clone().into_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+        {
+            let mut file = fs::File::create(&tmp_path)?;
+            file.write_all(contents)?;
+            file.sync_all()?;
+        }
+        if self.backups > 0 && self.path.exists() {
+            for n in (1..self.backups).rev() {
+                let older = self.backup_path(n);
+                if older.exists() {
+                    fs::rename(&older, self.backup_path(n + 1))?;
+                }
+            }
+            fs::copy(&self.path, self.backup_path(1))?;
+        }
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+fn parse_number(arg: Option<String>, option: &str) -> Result<u64, String> {
+    arg.and_then(|arg| arg.parse().ok()).ok_or(format!(
+        "{} requires a number",
+        option
+    ))
+}
@@ -0,0 +1,385 @@
+use std::sync::{Arc, Weak};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::mem;
+
+use blueprint::Blueprint;
+use machine::{Machine, MachineStatus};
+use vm::Vm;
+use Frame;
+use Object;
+use ObjectCell;
+use LinkTerminator;
+use FrameParam;
+
+/// Objects that must run one after another - producers first, the requested object last.
+pub struct Job {
+    id: u64,
+    pending: VecDeque<Weak<RefCell<Object>>>,
+    /// Run that must finish before the next pending object starts.
+    waiting: Option<u64>,
+    /// Run of another object that ends together with the job - see `start_running_job`.
+    run: Option<u64>,
+}
+
+enum Progress {
+    Waiting,
+    /// Whether every run of the job succeeded.
+    Done(bool),
+}
+
+/// Runs of one frame on several machines, at most `Vm::machine_concurrency` at a time.
+pub struct Batch {
+    queued: VecDeque<Weak<RefCell<Object>>>,
+    /// Jobs started for each machine.
+    running: Vec<(u64, Weak<RefCell<Machine>>)>,
+}
+
+/// Frames whose outputs are linked to `frame`.
+pub fn producers(blueprint: &Blueprint, frame: &Arc<RefCell<Frame>>) -> Vec<Arc<RefCell<Frame>>> {
+    let mut producers: Vec<Arc<RefCell<Frame>>> = Vec::new();
+    for link in blueprint.links.iter() {
+        let link = link.borrow();
+        match (&link.a, &link.b) {
+            (&LinkTerminator::FrameParam(FrameParam { frame: ref producer, param_index }),
+             &LinkTerminator::Frame(ref target)) if Arc::ptr_eq(target, frame) => {
+                let output = producer.borrow().parameters().get(param_index).map_or(
+                    false,
+                    |param| param.output,
+                );
+                if output && !producers.iter().any(|other| Arc::ptr_eq(other, producer)) {
+                    producers.push(producer.clone());
+                }
+            }
+            _ => (),
+        }
+    }
+    producers
+}
+
+/// Frames that must run before `frame` - producers of whatever is linked to its runnable
+/// parameters.
+fn dependencies(blueprint: &Blueprint, frame: &Arc<RefCell<Frame>>) -> Vec<Arc<RefCell<Frame>>> {
+    let parameters = frame.borrow().parameters().into_owned();
+    let mut dependencies: Vec<Arc<RefCell<Frame>>> = Vec::new();
+    for link in blueprint.links.iter() {
+        let link = link.borrow();
+        match (&link.a, &link.b) {
+            (&LinkTerminator::FrameParam(FrameParam { frame: ref consumer, param_index }),
+             &LinkTerminator::Frame(ref input)) if Arc::ptr_eq(consumer, frame) => {
+                if !parameters.get(param_index).map_or(false, |param| param.runnable) {
+                    continue;
+                }
+                for producer in producers(blueprint, input) {
+                    if !dependencies.iter().any(|other| Arc::ptr_eq(other, &producer)) {
+                        dependencies.push(producer);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    dependencies
+}
+
+/// Orders `frame` and everything it depends on so that producers come before their consumers.
+///
+/// Fails with a description of the cycle if a frame depends on itself.
+fn order(
+    blueprint: &Blueprint,
+    frame: &Arc<RefCell<Frame>>,
+) -> Result<Vec<Arc<RefCell<Frame>>>, String> {
+    fn visit(
+        blueprint: &Blueprint,
+        frame: &Arc<RefCell<Frame>>,
+        path: &mut Vec<Arc<RefCell<Frame>>>,
+        sorted: &mut Vec<Arc<RefCell<Frame>>>,
+    ) -> Result<(), String> {
+        if sorted.iter().any(|done| Arc::ptr_eq(done, frame)) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|other| Arc::ptr_eq(other, frame)) {
+            let names: Vec<String> = path[start..]
+                .iter()
+                .chain(Some(frame))
+                .map(|frame| frame.borrow().title())
+                .collect();
+            return Err(format!("Cycle in blueprint {}: {}", blueprint.name, names.join(" <- ")));
+        }
+        path.push(frame.clone());
+        for dependency in dependencies(blueprint, frame) {
+            visit(blueprint, &dependency, path, sorted)?;
+        }
+        path.pop();
+        sorted.push(frame.clone());
+        Ok(())
+    }
+    let mut sorted = Vec::new();
+    visit(blueprint, frame, &mut Vec::new(), &mut sorted)?;
+    Ok(sorted)
+}
+
+/// Objects of `object`'s machine to run in order to run `object`.
+pub fn plan(object: &ObjectCell) -> Result<Vec<ObjectCell>, String> {
+    let (frame, machine) = {
+        let object = object.borrow();
+        (object.frame.clone(), object.machine.upgrade().unwrap())
+    };
+    let blueprint = frame.borrow().blueprint.upgrade().unwrap();
+    let frames = order(&blueprint.borrow(), &frame)?;
+    Ok(frames
+        .iter()
+        .filter_map(|frame| machine.borrow().object_for(frame))
+        .collect())
+}
+
+impl Vm {
+    /// Runs `object` once everything it depends on has run. Returns the id of the job, whose
+    /// result ends up in `job_results`.
+    pub fn schedule(&mut self, object: &ObjectCell) -> u64 {
+        self.last_job_id += 1;
+        let id = self.last_job_id;
+        match plan(object) {
+            Ok(objects) => {
+                self.continue_job(Job {
+                    id: id,
+                    pending: objects.iter().map(Arc::downgrade).collect(),
+                    waiting: None,
+                    run: None,
+                });
+            }
+            Err(err) => {
+                let typ = object.borrow().typ();
+                typ.fail(object, err);
+                self.job_results.insert(id, false);
+            }
+        }
+        id
+    }
+
+    /// Starts the next objects of jobs whose previous run has finished.
+    ///
+    /// Called whenever a run may have finished.
+    pub fn advance_jobs(&mut self) {
+        // Runs finished by jobs may let jobs that were already advanced continue.
+        let mut finished_run = true;
+        while finished_run {
+            finished_run = false;
+            let jobs = mem::replace(&mut self.jobs, Vec::new());
+            for job in jobs {
+                finished_run |= self.continue_job(job);
+            }
+        }
+        self.advance_batches();
+    }
+
+    /// Registers a run of `o` that finishes when job `job` does, with the same result.
+    pub fn start_running_job(&mut self, o: &ObjectCell, job: u64) {
+        let run_id = self.start_running(o);
+        if let Some(job) = self.jobs.iter_mut().find(|other| other.id == job) {
+            job.run = Some(run_id);
+            return;
+        }
+        let succeeded = self.job_results.get(&job).cloned().unwrap_or(false);
+        self.finish_running(run_id, succeeded);
+    }
+
+    /// Returns whether the job finished a run.
+    fn continue_job(&mut self, mut job: Job) -> bool {
+        match self.advance(&mut job) {
+            Progress::Waiting => {
+                self.jobs.push(job);
+                false
+            }
+            Progress::Done(succeeded) => {
+                self.job_results.insert(job.id, succeeded);
+                match job.run {
+                    Some(run_id) => {
+                        self.finish_running(run_id, succeeded);
+                        true
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+
+    fn advance(&mut self, job: &mut Job) -> Progress {
+        loop {
+            if let Some(run_id) = job.waiting {
+                if self.running(run_id) {
+                    return Progress::Waiting;
+                }
+                job.waiting = None;
+                if self.take_failure(run_id) {
+                    let consumer = job.pending.back().and_then(Weak::upgrade);
+                    if let Some(consumer) = consumer {
+                        let title = consumer.borrow().frame.borrow().title();
+                        println!("Not running {} - its input failed", title);
+                    }
+                    return Progress::Done(false);
+                }
+            }
+            let object = match job.pending.pop_front() {
+                Some(object) => object,
+                None => return Progress::Done(true),
+            };
+            let object = match object.upgrade() {
+                Some(object) => object,
+                None => continue,
+            };
+            // Objects that are already running are waited for rather than restarted.
+            job.waiting = match self.current_run(&object) {
+                Some(run_id) => Some(run_id),
+                None => {
+                    let last_run_id = self.last_run_id;
+                    self.run_object(&object);
+                    // Runs that failed to start are finished by the time `run_object` returns.
+                    self.current_run(&object).or(if self.last_run_id > last_run_id {
+                        Some(self.last_run_id)
+                    } else {
+                        None
+                    })
+                }
+            };
+        }
+    }
+
+    /// Runs `frame` on each of `machines`. The progress is shown in `Machine::status`.
+    pub fn run_on_machines(
+        &mut self,
+        frame: &Arc<RefCell<Frame>>,
+        machines: &[Arc<RefCell<Machine>>],
+    ) {
+        if frame.borrow().global {
+            println!(
+                "{} is global - its only object runs once, on the first machine",
+                frame.borrow().title()
+            );
+        }
+        let mut queued = VecDeque::new();
+        for machine in machines.iter() {
+            // Global frames have a single object, on the first machine.
+            let object = machine.borrow().find_object(frame);
+            if let Some(object) = object {
+                machine.borrow_mut().status = Some(MachineStatus::Queued);
+                queued.push_back(Arc::downgrade(&object));
+            }
+        }
+        self.batches.push(Batch {
+            queued: queued,
+            running: Vec::new(),
+        });
+        self.advance_batches();
+    }
+
+    /// Records the results of finished jobs of batches and starts queued ones.
+    fn advance_batches(&mut self) {
+        let mut batches = mem::replace(&mut self.batches, Vec::new());
+        for batch in batches.iter_mut() {
+            self.advance_batch(batch);
+        }
+        batches.retain(|batch| !batch.queued.is_empty() || !batch.running.is_empty());
+        self.batches = batches;
+        // Results of jobs outside of batches aren't needed.
+        self.job_results.clear();
+    }
+
+    fn advance_batch(&mut self, batch: &mut Batch) {
+        loop {
+            {
+                let results = &self.job_results;
+                batch.running.retain(|&(job, ref machine)| {
+                    let status = match results.get(&job) {
+                        Some(&true) => MachineStatus::Succeeded,
+                        Some(&false) => MachineStatus::Failed,
+                        None => return true,
+                    };
+                    if let Some(machine) = machine.upgrade() {
+                        machine.borrow_mut().status = Some(status);
+                    }
+                    false
+                });
+            }
+            if batch.running.len() >= self.machine_concurrency {
+                return;
+            }
+            let object = match batch.queued.pop_front().and_then(|object| object.upgrade()) {
+                Some(object) => object,
+                None if batch.queued.is_empty() => return,
+                None => continue,
+            };
+            let machine = object.borrow().machine.clone();
+            if let Some(machine) = machine.upgrade() {
+                machine.borrow_mut().status = Some(MachineStatus::Running);
+            }
+            let job = self.schedule(&object);
+            batch.running.push((job, machine));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixtures;
+    use fixtures::{param, text};
+
+    fn link(blueprint: &Arc<RefCell<Blueprint>>, a: LinkTerminator, b: &Arc<RefCell<Frame>>) {
+        fixtures::link(blueprint, a, LinkTerminator::Frame(b.clone()), 0);
+    }
+
+    /// `printf` writes "hello" into a text that `echo` receives as its argument.
+    #[test]
+    fn producers_run_first() {
+        let (vm, blueprint) = fixtures::blueprint("Pipeline");
+        let printf = fixtures::frame(&blueprint, "Process", true);
+        let echo = fixtures::frame(&blueprint, "Process", true);
+        let (printf_command, echo_command) = (text(&blueprint, "printf"), text(&blueprint, "echo"));
+        let hello = text(&blueprint, "hello");
+        let (argument, output) = (text(&blueprint, ""), text(&blueprint, ""));
+        link(&blueprint, param(&printf, 0), &printf_command);
+        link(&blueprint, param(&printf, 1), &hello);
+        link(&blueprint, param(&printf, 3), &argument);
+        link(&blueprint, param(&echo, 0), &echo_command);
+        link(&blueprint, param(&echo, 1), &argument);
+        link(&blueprint, param(&echo, 3), &output);
+
+        let object = blueprint.borrow().get_object(&echo);
+        let planned = plan(&object).unwrap();
+        assert_eq!(planned.len(), 2);
+        assert!(Arc::ptr_eq(&planned[0].borrow().frame, &printf));
+
+        let output = blueprint.borrow().get_object(&output);
+        let mut vm = vm.borrow_mut();
+        vm.schedule(&object);
+        vm.run_until(|vm| vm.jobs.is_empty());
+        assert_eq!(output.borrow().data.downcast_ref::<String>().unwrap(), "hello\n");
+    }
+
+    #[test]
+    fn failing_to_start_fails_the_job() {
+        let (vm, blueprint) = fixtures::blueprint("Broken");
+        let process = fixtures::frame(&blueprint, "Process", true);
+
+        let object = blueprint.borrow().get_object(&process);
+        let mut vm = vm.borrow_mut();
+        let job = vm.schedule(&object);
+        assert_eq!(vm.job_results.get(&job), Some(&false));
+    }
+
+    #[test]
+    fn cycles_are_reported() {
+        let (_vm, blueprint) = fixtures::blueprint("Loop");
+        let process = fixtures::frame(&blueprint, "Process", true);
+        let data = text(&blueprint, "");
+        link(&blueprint, param(&process, 2), &data);
+        link(&blueprint, param(&process, 3), &data);
+
+        let object = blueprint.borrow().get_object(&process);
+        assert_eq!(
+            plan(&object).err().unwrap(),
+            "Cycle in blueprint Loop: Process <- Process"
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use std::sync::{Arc, Weak};
+use std::cell::RefCell;
+use std::time;
+
+use euclid::ScaleFactor;
+use blueprint_list::Rename;
+use menu::VisibleMenu;
+use rendering::{FrameState, Protocol};
+use Display;
+use DisplayMillimetreSpace;
+use DisplayPoint;
+use PixelPoint;
+use TouchReceiver;
+use WorldMillimetreSpace;
+use WorldPoint;
+use PARAM_RADIUS;
+
+/// View of one websocket client - its viewport, pointer and open menus.
+///
+/// Blueprints, machines and the active blueprint are shared by all clients.
+pub struct Session {
+    pub display: Display,
+    pub center: Arc<RefCell<WorldPoint>>,
+    pub mouse: PixelPoint,
+    pub last_update: time::Instant,
+    pub mouse_handler: Option<Box<TouchReceiver>>,
+    pub menus: Vec<Weak<VisibleMenu>>,
+    /// Blueprint whose name the client is typing in.
+    pub renaming: Option<Rename>,
+    pub zoom: ScaleFactor<f64, DisplayMillimetreSpace, WorldMillimetreSpace>,
+    pub protocol: Protocol,
+    /// Display lists of `LAYERS` last sent to the client.
+    pub layers: Vec<Vec<u8>>,
+    pub frame_state: FrameState,
+    /// Whether the view changed since the last rendering was sent.
+    pub pending: bool,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session {
+            display: Display {
+                size: PixelPoint::new(1024., 768.),
+                dpi: 96.,
+                eye_distance_meters: 0.5,
+            },
+            center: Arc::new(RefCell::new(WorldPoint::new(0., 0.))),
+            mouse: PixelPoint::new(0., 0.),
+            last_update: time::Instant::now(),
+            mouse_handler: None,
+            menus: Vec::new(),
+            renaming: None,
+            zoom: ScaleFactor::new(1.0),
+            protocol: Protocol::Json,
+            layers: Vec::new(),
+            frame_state: FrameState::Idle,
+            pending: false,
+        }
+    }
+
+    pub fn mouse_display(&self) -> DisplayPoint {
+        self.display.to_millimetre(self.mouse)
+    }
+
+    pub fn mouse_world(&self) -> WorldPoint {
+        self.mouse_display() * self.zoom - *self.center.borrow()
+    }
+
+    /// Top-left corner of the screen overlay (machine circles & blueprint list), in display space.
+    pub fn overlay_corner(&self) -> DisplayPoint {
+        let pixel_scale = self.display.pixel_size().get();
+        let half_width = self.display.size.x * 0.5 * pixel_scale;
+        let half_height = self.display.size.y * 0.5 * pixel_scale;
+        DisplayPoint::new(
+            -half_width + PARAM_RADIUS * 2.,
+            -half_height + PARAM_RADIUS * 2.,
+        )
+    }
+}
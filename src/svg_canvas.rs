@@ -0,0 +1,612 @@
+extern crate rusttype;
+
+use std::sync::Arc;
+use std::cell::RefCell;
+use std::f64::consts::PI;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::io::Write as IoWrite;
+use std::path::{Path, PathBuf};
+use canvas::Canvas;
+use self::rusttype::Font;
+
+use blueprint::Blueprint;
+use vm::Vm;
+use LinkTerminator;
+use Visible;
+use PARAM_RADIUS;
+use PARAM_SPACING;
+
+/// Affine transform - maps (x, y) to (a * x + c * y + e, b * x + d * y + f), like the canvas.
+#[derive(Clone, Copy)]
+struct Transform {
+    a: f64,
+    b: f64,
+    c: f64,
+    d: f64,
+    e: f64,
+    f: f64,
+}
+
+impl Transform {
+    fn apply(&self, x: f64, y: f64) -> (f64, f64) {
+        (
+            self.a * x + self.c * y + self.e,
+            self.b * x + self.d * y + self.f,
+        )
+    }
+    /// Length of a unit vector after the transform - it only translates, rotates & scales
+    /// uniformly.
+    fn scale(&self) -> f64 {
+        (self.a * self.d - self.b * self.c).abs().sqrt()
+    }
+    fn angle(&self) -> f64 {
+        self.b.atan2(self.a)
+    }
+    fn mirrored(&self) -> bool {
+        self.a * self.d - self.b * self.c < 0.
+    }
+}
+
+/// Part of the canvas state saved by `save` and brought back by `restore`.
+#[derive(Clone)]
+struct State {
+    transform: Transform,
+    fill_style: String,
+    stroke_style: String,
+    line_width: f64,
+    line_dash: Vec<f64>,
+    font_size: f64,
+    font_family: String,
+    text_align: String,
+    text_baseline: String,
+    /// Id of the `<clipPath>` that applies to everything drawn.
+    clip: Option<usize>,
+}
+
+/// Canvas that renders to an SVG document, following the semantics of the HTML canvas.
+///
+/// Paths are transformed as they're built, so each element is written in document coordinates.
+/// Text is positioned with the metrics of the embedded Iosevka font.
+pub struct SvgCanvas<'a> {
+    width: f64,
+    height: f64,
+    body: String,
+    path: String,
+    /// Whether the current path has a current point.
+    path_open: bool,
+    state: State,
+    saved: Vec<State>,
+    clip_count: usize,
+    font: Arc<Font<'a>>,
+}
+
+/// Shortest representation of `x` with at most 3 decimals.
+fn num(x: f64) -> String {
+    let s = format!("{:.3}", x);
+    let s = s.trim_right_matches('0').trim_right_matches('.');
+    if s == "-0" {
+        "0".to_string()
+    } else {
+        s.to_string()
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl<'a> SvgCanvas<'a> {
+    /// Canvas of `width` x `height` millimetres.
+    pub fn new(font: Arc<Font<'a>>, width: f64, height: f64) -> SvgCanvas<'a> {
+        SvgCanvas {
+            width: width,
+            height: height,
+            body: String::new(),
+            path: String::new(),
+            path_open: false,
+            state: State {
+                transform: Transform {
+                    a: 1.,
+                    b: 0.,
+                    c: 0.,
+                    d: 1.,
+                    e: 0.,
+                    f: 0.,
+                },
+                fill_style: "#000".to_string(),
+                stroke_style: "#000".to_string(),
+                line_width: 1.,
+                line_dash: Vec::new(),
+                font_size: 10.,
+                font_family: "sans-serif".to_string(),
+                text_align: "start".to_string(),
+                text_baseline: "alphabetic".to_string(),
+                clip: None,
+            },
+            saved: Vec::new(),
+            clip_count: 0,
+            font: font,
+        }
+    }
+
+    pub fn serialize(self) -> String {
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}mm\" height=\"{h}mm\" \
+             viewBox=\"0 0 {w} {h}\">\n\
+             <rect width=\"{w}\" height=\"{h}\" fill=\"#ddd\"/>\n{}</svg>\n",
+            self.body,
+            w = num(self.width),
+            h = num(self.height)
+        )
+    }
+
+    fn clip_attribute(&self) -> String {
+        match self.state.clip {
+            Some(id) => format!(" clip-path=\"url(#clip{})\"", id),
+            None => String::new(),
+        }
+    }
+
+    fn move_to(&mut self, x: f64, y: f64) {
+        let (x, y) = self.state.transform.apply(x, y);
+        write!(self.path, "M{} {}", num(x), num(y)).unwrap();
+        self.path_open = true;
+    }
+
+    fn line_to(&mut self, x: f64, y: f64) {
+        if !self.path_open {
+            return self.move_to(x, y);
+        }
+        let (x, y) = self.state.transform.apply(x, y);
+        write!(self.path, "L{} {}", num(x), num(y)).unwrap();
+    }
+
+    /// Adds an elliptic arc around (x, y) from angle `start` to `end`, like the HTML canvas.
+    fn elliptic_arc(
+        &mut self,
+        (x, y): (f64, f64),
+        (rx, ry): (f64, f64),
+        rotation: f64,
+        (start, end): (f64, f64),
+        anticlockwise: bool,
+    ) {
+        // Same as browsers - an end angle behind the start one is reached by going around, so
+        // (0, 2π, anticlockwise) is a full circle.
+        let sweep = if !anticlockwise {
+            if end - start >= 2. * PI {
+                2. * PI
+            } else if start > end {
+                2. * PI - (start - end) % (2. * PI)
+            } else {
+                end - start
+            }
+        } else if start - end >= 2. * PI {
+            -2. * PI
+        } else if start < end {
+            -(2. * PI - (end - start) % (2. * PI))
+        } else {
+            end - start
+        };
+        let (sin, cos) = rotation.sin_cos();
+        let point = |t: f64| {
+            let (px, py) = (rx * t.cos(), ry * t.sin());
+            (x + px * cos - py * sin, y + px * sin + py * cos)
+        };
+        let (sx, sy) = point(start);
+        self.line_to(sx, sy);
+        // SVG arcs are drawn in segments of at most half a turn - a full turn has no unique arc.
+        let segments = (sweep.abs() / PI).ceil().max(1.) as usize;
+        let transform = self.state.transform;
+        let scale = transform.scale();
+        let clockwise = (sweep > 0.) != transform.mirrored();
+        let degrees = (rotation + transform.angle()) * 180. / PI;
+        for i in 1..segments + 1 {
+            let (px, py) = point(start + sweep * i as f64 / segments as f64);
+            let (px, py) = transform.apply(px, py);
+            write!(
+                self.path,
+                "A{} {} {} 0 {} {} {}",
+                num(rx * scale),
+                num(ry * scale),
+                num(degrees),
+                clockwise as u8,
+                num(px),
+                num(py)
+            ).unwrap();
+        }
+    }
+
+    fn dash_attribute(&self) -> String {
+        if self.state.line_dash.is_empty() {
+            return String::new();
+        }
+        let scale = self.state.transform.scale();
+        let dash: Vec<String> = self.state
+            .line_dash
+            .iter()
+            .map(|&d| num(d * scale))
+            .collect();
+        format!(" stroke-dasharray=\"{}\"", dash.join(" "))
+    }
+
+    fn text_width(&self, text: &str) -> f64 {
+        let scale = rusttype::Scale::uniform(self.state.font_size as f32);
+        text.chars()
+            .filter_map(|c| self.font.glyph(c))
+            .map(|glyph| glyph.scaled(scale).h_metrics().advance_width as f64)
+            .sum()
+    }
+}
+
+impl<'a> Canvas for SvgCanvas<'a> {
+    fn get_font_metrics(&self, scale: f64) -> rusttype::VMetrics {
+        self.font.v_metrics(rusttype::Scale {
+            x: scale as f32,
+            y: scale as f32,
+        })
+    }
+    fn translate(&mut self, x: f64, y: f64) -> &mut Canvas {
+        {
+            let t = &mut self.state.transform;
+            t.e += t.a * x + t.c * y;
+            t.f += t.b * x + t.d * y;
+        }
+        self
+    }
+    fn fillText(&mut self, text: &str, x: f64, y: f64) -> &mut Canvas {
+        let width = self.text_width(text);
+        let x = match self.state.text_align.as_ref() {
+            "center" => x - width / 2.,
+            "right" | "end" => x - width,
+            _ => x,
+        };
+        let metrics = self.get_font_metrics(self.state.font_size);
+        let (ascent, descent) = (metrics.ascent as f64, metrics.descent as f64);
+        let y = match self.state.text_baseline.as_ref() {
+            "top" | "hanging" => y + ascent,
+            "middle" => y + (ascent + descent) / 2.,
+            "bottom" | "ideographic" => y + descent,
+            _ => y,
+        };
+        let t = self.state.transform;
+        // The clip path is wrapped around the text so that its transform doesn't apply to the clip.
+        write!(
+            self.body,
+            "<g{}><text transform=\"matrix({} {} {} {} {} {})\" x=\"{}\" y=\"{}\" \
+             font-family=\"{}\" font-size=\"{}\" fill=\"{}\" xml:space=\"preserve\">\
+             {}</text></g>\n",
+            self.clip_attribute(),
+            num(t.a),
+            num(t.b),
+            num(t.c),
+            num(t.d),
+            num(t.e),
+            num(t.f),
+            num(x),
+            num(y),
+            escape(&self.state.font_family),
+            num(self.state.font_size),
+            escape(&self.state.fill_style),
+            escape(text)
+        ).unwrap();
+        self
+    }
+    fn fillRect(&mut self, x: f64, y: f64, w: f64, h: f64) -> &mut Canvas {
+        let (path, path_open) = (self.path.clone(), self.path_open);
+        self.beginPath().rect(x, y, w, h).fill();
+        self.path = path;
+        self.path_open = path_open;
+        self
+    }
+    fn rect(&mut self, x: f64, y: f64, w: f64, h: f64) -> &mut Canvas {
+        self.move_to(x, y);
+        self.line_to(x + w, y);
+        self.line_to(x + w, y + h);
+        self.line_to(x, y + h);
+        self.closePath();
+        self.move_to(x, y);
+        self
+    }
+    /// The last argument is passed to the browser as `anticlockwise`, so it's treated as such.
+    fn arc(
+        &mut self,
+        x: f64,
+        y: f64,
+        r: f64,
+        alpha: f64,
+        beta: f64,
+        clockwise: bool,
+    ) -> &mut Canvas {
+        self.elliptic_arc((x, y), (r, r), 0., (alpha, beta), clockwise);
+        self
+    }
+    fn ellipse(
+        &mut self,
+        x: f64,
+        y: f64,
+        rx: f64,
+        ry: f64,
+        rotation: f64,
+        alpha: f64,
+        beta: f64,
+        anticlockwise: bool,
+    ) -> &mut Canvas {
+        self.elliptic_arc((x, y), (rx, ry), rotation, (alpha, beta), anticlockwise);
+        self
+    }
+    fn moveTo(&mut self, x: f64, y: f64) -> &mut Canvas {
+        self.move_to(x, y);
+        self
+    }
+    fn lineTo(&mut self, x: f64, y: f64) -> &mut Canvas {
+        self.line_to(x, y);
+        self
+    }
+    fn setLineDash(&mut self, dash: &Vec<f64>) -> &mut Canvas {
+        self.state.line_dash = dash.clone();
+        self
+    }
+    fn rotate(&mut self, alpha: f64) -> &mut Canvas {
+        {
+            let t = &mut self.state.transform;
+            let (sin, cos) = alpha.sin_cos();
+            let (a, b, c, d) = (t.a, t.b, t.c, t.d);
+            t.a = a * cos + c * sin;
+            t.b = b * cos + d * sin;
+            t.c = c * cos - a * sin;
+            t.d = d * cos - b * sin;
+        }
+        self
+    }
+    fn scale(&mut self, scale: f64) -> &mut Canvas {
+        {
+            let t = &mut self.state.transform;
+            t.a *= scale;
+            t.b *= scale;
+            t.c *= scale;
+            t.d *= scale;
+        }
+        self
+    }
+    fn fillStyle(&mut self, style: &str) -> &mut Canvas {
+        self.state.fill_style = style.to_string();
+        self
+    }
+    fn textAlign(&mut self, align: &str) -> &mut Canvas {
+        self.state.text_align = align.to_string();
+        self
+    }
+    fn textBaseline(&mut self, baseline: &str) -> &mut Canvas {
+        self.state.text_baseline = baseline.to_string();
+        self
+    }
+    fn lineWidth(&mut self, width: f64) -> &mut Canvas {
+        self.state.line_width = width;
+        self
+    }
+    fn strokeStyle(&mut self, style: &str) -> &mut Canvas {
+        self.state.stroke_style = style.to_string();
+        self
+    }
+    /// Understands fonts like "6px Iosevka".
+    fn font(&mut self, font: &str) -> &mut Canvas {
+        let mut parts = font.splitn(2, ' ');
+        let size = parts.next().and_then(|size| size.trim_right_matches("px").parse().ok());
+        if let (Some(size), Some(family)) = (size, parts.next()) {
+            self.state.font_size = size;
+            self.state.font_family = family.to_string();
+        }
+        self
+    }
+    fn save(&mut self) -> &mut Canvas {
+        self.saved.push(self.state.clone());
+        self
+    }
+    fn restore(&mut self) -> &mut Canvas {
+        if let Some(state) = self.saved.pop() {
+            self.state = state;
+        }
+        self
+    }
+    fn beginPath(&mut self) -> &mut Canvas {
+        self.path.clear();
+        self.path_open = false;
+        self
+    }
+    fn closePath(&mut self) -> &mut Canvas {
+        if self.path_open {
+            self.path.push('Z');
+        }
+        self
+    }
+    fn fill(&mut self) -> &mut Canvas {
+        if !self.path.is_empty() {
+            write!(
+                self.body,
+                "<path d=\"{}\" fill=\"{}\"{}/>\n",
+                self.path,
+                escape(&self.state.fill_style),
+                self.clip_attribute()
+            ).unwrap();
+        }
+        self
+    }
+    fn stroke(&mut self) -> &mut Canvas {
+        if !self.path.is_empty() {
+            write!(
+                self.body,
+                "<path d=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{}\"{}{}/>\n",
+                self.path,
+                escape(&self.state.stroke_style),
+                num(self.state.line_width * self.state.transform.scale()),
+                self.dash_attribute(),
+                self.clip_attribute()
+            ).unwrap();
+        }
+        self
+    }
+    /// Intersects the clipping region with the current path, until the state is restored.
+    fn clip(&mut self) -> &mut Canvas {
+        let id = self.clip_count;
+        self.clip_count += 1;
+        write!(
+            self.body,
+            "<clipPath id=\"clip{}\"{}><path d=\"{}\"/></clipPath>\n",
+            id,
+            self.clip_attribute(),
+            self.path
+        ).unwrap();
+        self.state.clip = Some(id);
+        self
+    }
+}
+
+/// Room around the frames for parameter labels and link ends.
+const MARGIN: f64 = 50.;
+
+/// Frames & links of `blueprint`, cropped to their bounds.
+pub fn blueprint_svg(blueprint: &Arc<RefCell<Blueprint>>, font: Arc<Font<'static>>) -> String {
+    let blueprint = blueprint.borrow();
+    let mut points = vec![];
+    for frame in blueprint.frames.iter() {
+        let frame = frame.borrow();
+        let params = frame.parameters().len() as f64;
+        let half = frame.size * 0.5;
+        points.push((frame.pos.x - half.width, frame.pos.y - half.height));
+        let bottom = frame.pos.y + half.height + (PARAM_RADIUS * 2. + PARAM_SPACING) * params;
+        points.push((frame.pos.x + half.width, bottom));
+    }
+    for link in blueprint.links.iter() {
+        let link = link.borrow();
+        for end in [&link.a, &link.b].iter() {
+            if let &&LinkTerminator::Point(point) = end {
+                points.push((point.x, point.y));
+            }
+        }
+    }
+    let (mut min_x, mut min_y) = points.first().cloned().unwrap_or((0., 0.));
+    let (mut max_x, mut max_y) = (min_x, min_y);
+    for &(x, y) in points.iter() {
+        min_x = min_x.min(x);
+        max_x = max_x.max(x);
+        min_y = min_y.min(y);
+        max_y = max_y.max(y);
+    }
+    let width = max_x - min_x + MARGIN * 2.;
+    let height = max_y - min_y + MARGIN * 2.;
+    let mut c = SvgCanvas::new(font, width, height);
+    c.font("6px Iosevka");
+    c.translate(MARGIN - min_x, MARGIN - min_y);
+    for frame in blueprint.frames.iter() {
+        c.save();
+        frame.draw(&mut c);
+        c.restore();
+    }
+    for link in blueprint.links.iter() {
+        c.save();
+        link.draw(&mut c);
+        c.restore();
+    }
+    c.serialize()
+}
+
+/// Writes every blueprint of `vm` to `dir`, as `<name>.svg`.
+pub fn export_svgs(vm: &Vm, dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    let mut written: Vec<PathBuf> = Vec::new();
+    for blueprint in vm.blueprints.iter() {
+        let mut name: String = blueprint
+            .borrow()
+            .name
+            .chars()
+            .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' || c == ' ' {
+                c
+            } else {
+                '_'
+            })
+            .collect();
+        let mut path = dir.join(format!("{}.svg", name));
+        if written.contains(&path) {
+            name.push_str(&format!(" {}", blueprint.borrow().id));
+            path = dir.join(format!("{}.svg", name));
+        }
+        let mut file = fs::File::create(&path)?;
+        file.write_all(blueprint_svg(blueprint, vm.font()).as_bytes())?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn font() -> Arc<Font<'static>> {
+        let collection = rusttype::FontCollection::from_bytes(&include_bytes!(
+            "html/fonts/iosevka-regular.ttf"
+        )[..]);
+        Arc::new(collection.into_font().unwrap())
+    }
+
+    #[test]
+    fn transforms_paths() {
+        let mut c = SvgCanvas::new(font(), 100., 100.);
+        c.save();
+        c.translate(10., 20.).scale(2.).lineWidth(0.5);
+        c.setLineDash(&vec![1., 2.]);
+        c.beginPath().moveTo(0., 0.).lineTo(5., 0.).stroke();
+        c.restore();
+        c.beginPath().moveTo(0., 0.).lineTo(5., 0.).stroke();
+        let svg = c.serialize();
+        assert!(svg.contains(
+            "<path d=\"M10 20L20 20\" fill=\"none\" stroke=\"#000\" stroke-width=\"1\" \
+             stroke-dasharray=\"2 4\"/>"
+        ));
+        assert!(svg.contains(
+            "<path d=\"M0 0L5 0\" fill=\"none\" stroke=\"#000\" stroke-width=\"1\"/>"
+        ));
+    }
+
+    #[test]
+    fn full_circle_is_split_in_two_arcs() {
+        let mut c = SvgCanvas::new(font(), 100., 100.);
+        c.beginPath().arc(0., 0., 10., 0., 2. * PI, false).fill();
+        c.beginPath().arc(0., 0., 10., 0., 2. * PI, true).fill();
+        let svg = c.serialize();
+        assert!(svg.contains("d=\"M10 0A10 10 0 0 1 -10 0A10 10 0 0 1 10 0\""));
+        assert!(svg.contains("d=\"M10 0A10 10 0 0 0 -10 0A10 10 0 0 0 10 0\""));
+    }
+
+    #[test]
+    fn clip_lasts_until_restore() {
+        let mut c = SvgCanvas::new(font(), 100., 100.);
+        c.save();
+        c.beginPath().rect(0., 0., 10., 10.).clip();
+        c.fillRect(5., 5., 10., 10.);
+        c.fillText("Clipped", 0., 0.);
+        c.restore();
+        c.fillRect(5., 5., 10., 10.);
+        let svg = c.serialize();
+        assert!(svg.contains("<clipPath id=\"clip0\"><path d=\"M0 0L10 0L10 10L0 10ZM0 0\"/>"));
+        assert_eq!(svg.matches("clip-path=\"url(#clip0)\"").count(), 2);
+        assert!(svg.contains("<path d=\"M5 5L15 5L15 15L5 15ZM5 5\" fill=\"#000\"/>"));
+    }
+
+    #[test]
+    fn exports_every_blueprint() {
+        let vm = Vm::new_headless();
+        let json = serde_json::from_str(include_str!("fixtures/vm_v4.json")).unwrap();
+        Vm::load_value(&vm, json).unwrap();
+        let vm = vm.borrow();
+        let main = vm.blueprints.iter().find(|b| b.borrow().name == "Main").unwrap();
+        let svg = blueprint_svg(main, vm.font());
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.contains("font-family=\"Iosevka\""));
+    }
+}
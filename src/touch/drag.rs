@@ -1,8 +1,10 @@
-use std::sync::Weak;
+use std::sync::{Arc, Weak};
 use std::cell::RefCell;
 
 use Frame;
 use WorldPoint;
+use WorldSize;
+use history::Edit;
 use DisplayPoint;
 use TouchReceiver;
 use vm::Vm;
@@ -19,6 +21,8 @@ pub struct DragFrame {
     pub horizontal: DragMode,
     pub frame: Weak<RefCell<Frame>>,
     pub pos: WorldPoint,
+    pub start_pos: WorldPoint,
+    pub start_size: WorldSize,
 }
 
 impl TouchReceiver for DragFrame {
@@ -62,5 +66,28 @@ impl TouchReceiver for DragFrame {
         self.pos = new_pos;
         return Some(self);
     }
-    fn end_touch(self: Box<Self>, _: &mut Vm) {}
+    fn end_touch(self: Box<Self>, vm: &mut Vm) {
+        let frame_rc = match self.frame.upgrade() {
+            Some(frame_rc) => frame_rc,
+            None => return,
+        };
+        let (pos, size, blueprint) = {
+            let frame = frame_rc.borrow();
+            (frame.pos, frame.size, frame.blueprint.upgrade())
+        };
+        if let Some(blueprint) = blueprint {
+            let mut blueprint = blueprint.borrow_mut();
+            if pos != self.start_pos || size != self.start_size {
+                blueprint.history.record(vm.client, Edit::Reshape {
+                    frame: frame_rc.clone(),
+                    before: (self.start_pos, self.start_size),
+                    after: (pos, size),
+                });
+            }
+            blueprint.history.commit(vm.client);
+        }
+    }
+    fn frame(&self) -> Option<Arc<RefCell<Frame>>> {
+        self.frame.upgrade()
+    }
 }
@@ -1,11 +1,13 @@
 use std::sync::Arc;
 use std::cell::RefCell;
 
+use Frame;
 use Link;
 use WorldPoint;
 use DisplayPoint;
 use TouchReceiver;
 use LinkTerminator;
+use link_error;
 use vm::Vm;
 
 #[derive(Clone, Copy)]
@@ -41,17 +43,33 @@ impl TouchReceiver for DragLink {
         self.pos = new_pos;
         Some(self)
     }
-    fn end_touch(self: Box<Self>, _: &mut Vm) {
+    fn end_touch(self: Box<Self>, vm: &mut Vm) {
         let mut link = self.link.borrow_mut();
         let blueprint_rc = link.blueprint.upgrade().unwrap();
         let mut blueprint = blueprint_rc.borrow_mut();
-        let frame = blueprint.query_frame(self.pos);
+        let mut frame = blueprint.query_frame(self.pos);
+        let fixed = match self.side {
+            LinkSide::A => &link.b,
+            LinkSide::B => &link.a,
+        };
+        let error = match (fixed, &frame) {
+            (&LinkTerminator::FrameParam(ref param), &Some(ref target)) => {
+                link_error(param, target)
+            }
+            _ => None,
+        };
+        // Incompatible links are dropped like links that end in empty space.
+        if let Some(err) = error {
+            println!("{}", err);
+            frame = None;
+        }
         match frame {
             Some(frame) => {
                 match self.side {
                     LinkSide::A => link.a = LinkTerminator::Frame(frame),
                     LinkSide::B => link.b = LinkTerminator::Frame(frame),
                 }
+                blueprint.history.commit(vm.client);
             }
             None => {
                 let i = blueprint
@@ -62,7 +80,53 @@ impl TouchReceiver for DragLink {
                     .unwrap()
                     .0;
                 blueprint.links.swap_remove(i);
+                blueprint.history.discard(vm.client);
             }
         }
     }
+    /// The frame at the fixed end, which would take the link with it if it was deleted.
+    fn frame(&self) -> Option<Arc<RefCell<Frame>>> {
+        let link = self.link.borrow();
+        let fixed = match self.side {
+            LinkSide::A => &link.b,
+            LinkSide::B => &link.a,
+        };
+        match fixed {
+            &LinkTerminator::Frame(ref frame) => Some(frame.clone()),
+            &LinkTerminator::FrameParam(ref param) => Some(param.frame.clone()),
+            &LinkTerminator::Point(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixtures;
+
+    /// Drags a link from the Command of a process onto a frame of the type registered as
+    /// `type_name`. Returns whether the link was kept.
+    fn drop_command_on(type_name: &str) -> bool {
+        let (vm, blueprint) = fixtures::blueprint("Links");
+        let process = fixtures::frame(&blueprint, "Process", true);
+        let target = fixtures::frame(&blueprint, type_name, true);
+        let pos = WorldPoint::new(100., 100.);
+        target.borrow_mut().pos = pos;
+        let command = fixtures::param(&process, 0);
+        let link = fixtures::link(&blueprint, command, LinkTerminator::Point(pos), 0);
+        let drag = Box::new(DragLink {
+            side: LinkSide::B,
+            link: link,
+            pos: pos,
+        });
+        drag.end_touch(&mut vm.borrow_mut());
+        let kept = blueprint.borrow().links.len() == 1;
+        kept
+    }
+
+    #[test]
+    fn incompatible_links_are_rejected() {
+        assert!(drop_command_on("Text"));
+        assert!(!drop_command_on("Empty"));
+    }
 }
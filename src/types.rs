@@ -0,0 +1,164 @@
+use std::sync::Arc;
+use std::any::Any;
+use std::slice;
+use std::cell::RefCell;
+
+use canvas::Canvas;
+use menu::Entry;
+use output::{Kind, Value};
+use load::*;
+use vm::Vm;
+use Frame;
+use Object;
+use ObjectCell;
+use Parameter;
+use RunArgs;
+use Type;
+
+/// Behaviour of a kind of frame.
+///
+/// Types are shared by all frames of that kind and looked up by `name` when a blueprint is loaded,
+/// so the name must stay stable between runs.
+pub trait FrameType: Send + Sync {
+    fn name(&self) -> &str;
+    /// Kind of values held by objects of this type.
+    fn kind(&self) -> Kind;
+    /// Whether typing over frames of this type edits the `String` held by their objects.
+    fn editable(&self) -> bool {
+        false
+    }
+    fn parameters(&self) -> &[Parameter];
+    fn init(&self, o: &mut Object);
+    fn run(&self, vm: &mut Vm, o: &ObjectCell, args: RunArgs);
+    /// Receives `Event::RunUpdate`s sent by background work started in `run`.
+    fn update(&self, vm: &mut Vm, o: &ObjectCell, data: Box<Any + Send>) {}
+    /// Reports that `o` can't run, e.g. because it depends on itself.
+    fn fail(&self, o: &ObjectCell, err: String) {
+        println!("{}", err);
+    }
+    /// Extra entries of the menu shown over frames of this type.
+    fn menu(&self, frame: &Arc<RefCell<Frame>>) -> Vec<Entry> {
+        Vec::new()
+    }
+    fn draw(&self, o: &Object, canvas: &mut Canvas);
+    /// Replaces the data of `o` with `value`, or appends it. Called for objects linked to output
+    /// parameters - types that can't hold the value refuse it.
+    fn store(&self, o: &mut Object, value: &Value, append: bool) -> Result<(), String> {
+        Err(format!("{} can't store values", self.name()))
+    }
+    fn serialize(&self, o: &Object) -> Vec<u8>;
+    fn deserialize(&self, o: &mut Object, data: Vec<u8>) -> Result<(), String>;
+}
+
+/// Built-in types are defined with closures.
+impl FrameType for Type {
+    fn name(&self) -> &str {
+        self.name
+    }
+    fn kind(&self) -> Kind {
+        self.kind
+    }
+    fn editable(&self) -> bool {
+        self.editable
+    }
+    fn parameters(&self) -> &[Parameter] {
+        self.parameters
+    }
+    fn init(&self, o: &mut Object) {
+        (self.init)(o)
+    }
+    fn run(&self, vm: &mut Vm, o: &ObjectCell, args: RunArgs) {
+        (self.run)(vm, o, args)
+    }
+    fn update(&self, vm: &mut Vm, o: &ObjectCell, data: Box<Any + Send>) {
+        if let Some(update) = self.update {
+            update(vm, o, data);
+        }
+    }
+    fn fail(&self, o: &ObjectCell, err: String) {
+        match self.fail {
+            Some(fail) => fail(o, err),
+            None => println!("{}", err),
+        }
+    }
+    fn menu(&self, frame: &Arc<RefCell<Frame>>) -> Vec<Entry> {
+        self.menu.map_or(Vec::new(), |menu| menu(frame))
+    }
+    fn draw(&self, o: &Object, canvas: &mut Canvas) {
+        (self.draw)(o, canvas)
+    }
+    fn store(&self, o: &mut Object, value: &Value, append: bool) -> Result<(), String> {
+        match self.store {
+            Some(store) => store(o, value, append),
+            None => Err(format!("{} can't store values", self.name)),
+        }
+    }
+    fn serialize(&self, o: &Object) -> Vec<u8> {
+        (self.serialize)(o)
+    }
+    fn deserialize(&self, o: &mut Object, data: Vec<u8>) -> Result<(), String> {
+        (self.deserialize)(o, data)
+    }
+}
+
+/// Frame types that can be placed in blueprints, in the order they appear in menus.
+///
+/// Frames of the same type share the registered `Arc`. The "Blueprint" type of instances is
+/// registered too, but placed from the blueprint list rather than from menus.
+pub struct TypeRegistry {
+    types: Vec<Arc<FrameType>>,
+}
+
+impl TypeRegistry {
+    pub fn new() -> TypeRegistry {
+        TypeRegistry { types: Vec::new() }
+    }
+
+    /// Adds a type. Names must be unique - they identify the type in saved files.
+    pub fn register(&mut self, typ: Arc<FrameType>) -> Result<(), String> {
+        if self.get(typ.name()).is_some() {
+            return Err(format!("Type \"{}\" is already registered", typ.name()));
+        }
+        self.types.push(typ);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<FrameType>> {
+        self.types.iter().find(|typ| typ.name() == name).cloned()
+    }
+
+    /// Like `get`, but fails with an error that lists the known types.
+    pub fn find(&self, name: &str) -> LoadResult<Arc<FrameType>> {
+        self.get(name).ok_or_else(|| {
+            LoadError::new(LoadErrorKind::UnknownType(
+                name.to_string(),
+                self.types.iter().map(|typ| typ.name().to_string()).collect(),
+            ))
+        })
+    }
+
+    pub fn iter(&self) -> slice::Iter<Arc<FrameType>> {
+        self.types.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use text_type;
+
+    #[test]
+    fn register_and_find() {
+        let mut types = TypeRegistry::new();
+        types.register(Arc::new(text_type)).unwrap();
+        assert!(types.register(Arc::new(text_type)).is_err());
+        assert_eq!(types.find("Text").unwrap().name(), "Text");
+        match types.find("Missing") {
+            Err(LoadError { kind: LoadErrorKind::UnknownType(name, known), .. }) => {
+                assert_eq!(name, "Missing");
+                assert_eq!(known, vec!["Text".to_string()]);
+            }
+            _ => panic!("unknown type should be reported"),
+        }
+    }
+}
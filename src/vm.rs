@@ -4,7 +4,7 @@ extern crate serde_json;
 extern crate ref_eq;
 extern crate serde;
 
-use std::sync::{Arc, Weak};
+use std::sync::{Arc, Weak, Mutex};
 use std::cell::RefCell;
 use std::collections::{HashSet, HashMap, VecDeque};
 use std::sync::mpsc;
@@ -12,9 +12,11 @@ use std::net::TcpStream;
 use std::time;
 use std::thread;
 use std::process;
+use std::io;
 
 use blueprint::*;
 use json_canvas::*;
+use binary_canvas::BinaryCanvas;
 use canvas::*;
 use process::*;
 use empty_type;
@@ -22,7 +24,6 @@ use text_type;
 use menu::*;
 use RunArgs;
 use event::*;
-use Display;
 use WorldPoint;
 use DisplayPoint;
 use PixelPoint;
@@ -33,15 +34,43 @@ use FrameParam;
 use Visible;
 use ObjectCell;
 use euclid::ScaleFactor;
-use DisplayMillimetreSpace;
-use WorldMillimetreSpace;
-use Type;
+use types::*;
 use AddFrameAction;
 use http;
+use load::*;
+use migration::*;
+use save::SaveConfig;
+use id::*;
+use history::*;
+use blueprint_list::*;
+use machine_list::*;
+use nested::*;
+use session::Session;
+use presence::*;
+use rendering::*;
+use scheduler::{Job, Batch};
+use machine::Machine;
 use touch::*;
 
 static FONT: &'static [u8] = include_bytes!("html/fonts/iosevka-regular.ttf");
 
+/// Name of a key combination as used in `Entry::shortcuts`, e.g. "Ctrl+Shift+Z" or "Delete".
+fn shortcut_name(code: &str, ctrl: bool, shift: bool) -> String {
+    let mut name = String::new();
+    if ctrl {
+        name.push_str("Ctrl+");
+    }
+    if shift {
+        name.push_str("Shift+");
+    }
+    if code.starts_with("Key") {
+        name.push_str(&code[3..]);
+    } else {
+        name.push_str(code);
+    }
+    name
+}
+
 fn walk_visible<V: Visible, T, F: FnMut(&Visible) -> Option<T>>(v: &Vec<V>, mut f: F) -> Option<T> {
     for visible in v.iter() {
         let result = f(visible as &Visible);
@@ -53,11 +82,12 @@ fn walk_visible<V: Visible, T, F: FnMut(&Visible) -> Option<T>>(v: &Vec<V>, mut
 }
 
 pub struct Vm {
+    this: Weak<RefCell<Vm>>,
     pub blueprints: Vec<Arc<RefCell<Blueprint>>>,
     pub active_blueprint: Weak<RefCell<Blueprint>>,
 
     pub tasks: VecDeque<Weak<RefCell<Object>>>,
-    pub types: Vec<&'static Type>,
+    pub types: TypeRegistry,
 
     is_running: bool,
 
@@ -67,17 +97,30 @@ pub struct Vm {
     client_counter: i64,
     font: Arc<rusttype::Font<'static>>,
 
+    /// Runs that haven't finished yet.
     run_ids: HashMap<u64, Weak<RefCell<Object>>>,
-    last_run_id: u64,
-
-    // Per-client parameters:
-    display: Display,
-    center: Arc<RefCell<WorldPoint>>,
-    mouse: PixelPoint,
-    last_update: time::Instant,
-    mouse_handler: Option<Box<TouchReceiver>>,
-    menus: Vec<Weak<VisibleMenu>>,
-    zoom: ScaleFactor<f64, DisplayMillimetreSpace, WorldMillimetreSpace>,
+    failed_runs: HashSet<u64>,
+    pub last_run_id: u64,
+    pub jobs: Vec<Job>,
+    pub last_job_id: u64,
+    /// Whether finished jobs succeeded, until batches learn about them.
+    pub job_results: HashMap<u64, bool>,
+    pub batches: Vec<Batch>,
+    /// How many machines of a batch run at the same time.
+    pub machine_concurrency: usize,
+    /// Processes started by objects - killed when the VM quits.
+    pub children: Vec<Weak<Mutex<Option<process::Child>>>>,
+
+    pub save_config: SaveConfig,
+    last_save: time::Instant,
+    last_change: Option<time::Instant>,
+    /// Contents of the last save - periodic saves are skipped when nothing changed.
+    saved_state: String,
+
+    /// Views of the websocket clients, keyed by client id.
+    pub sessions: HashMap<i64, Session>,
+    /// Client whose event is being processed or whose view is being drawn.
+    pub client: i64,
 }
 
 use self::serde::ser::{Serialize, Serializer, SerializeSeq, SerializeStruct};
@@ -93,18 +136,30 @@ impl<'a> Serialize for Tasks<'a> {
     {
         use std::ops::Deref;
         let mut task_seq = serializer.serialize_seq(None)?;
+        let mut saved = Vec::new();
         for task in self.0.tasks.iter() {
-            if let Some(task) = task.upgrade() {
-                let task = task.borrow();
-                let frame = task.frame.borrow();
-                let blueprint = frame.blueprint.upgrade().unwrap();
-                let blueprint_index = self.0.blueprint_index(&blueprint);
-                let blueprint = blueprint.borrow();
-                let frame_index = blueprint.frame_index(&task.frame);
-                let machine = task.machine.upgrade().unwrap();
-                let machine_index = blueprint.machine_index(&machine);
-                let tuple = (blueprint_index, frame_index, machine_index);
+            let mut task = match task.upgrade() {
+                Some(task) => task,
+                None => continue,
+            };
+            let machine = task.borrow().machine.upgrade().unwrap();
+            let blueprint = machine.borrow().blueprint.upgrade().unwrap();
+            // Nested machines aren't addressable from the file, so the instance that contains
+            // the task is run again instead.
+            if blueprint.borrow().machine(machine.borrow().id).is_none() {
+                match self.0.top_level_instance(&machine) {
+                    Some(instance) => task = instance,
+                    None => continue,
+                }
+            }
+            let task = task.borrow();
+            let frame = task.frame.borrow();
+            let blueprint = frame.blueprint.upgrade().unwrap();
+            let machine_id = task.machine.upgrade().unwrap().borrow().id;
+            let tuple = (blueprint.borrow().id, frame.id, machine_id);
+            if !saved.contains(&tuple) {
                 task_seq.serialize_element(&tuple)?;
+                saved.push(tuple);
             }
         }
         task_seq.end()
@@ -116,14 +171,14 @@ impl Serialize for Vm {
     where
         S: Serializer,
     {
-        let mut serializer = serializer.serialize_struct("Vm", 3)?;
+        let mut serializer = serializer.serialize_struct("Vm", 4)?;
+        serializer.serialize_field("format_version", &FORMAT_VERSION)?;
         serializer.serialize_field(
             "blueprints",
             &SerializableVec(&self.blueprints),
         )?;
         let active_blueprint = self.active_blueprint.upgrade().unwrap();
-        let active_blueprint = self.blueprint_index(&active_blueprint);
-        serializer.serialize_field("active_blueprint", &active_blueprint);
+        serializer.serialize_field("active_blueprint", &active_blueprint.borrow().id);
         serializer.serialize_field("tasks", &Tasks(self));
         serializer.end()
     }
@@ -135,18 +190,44 @@ use std::error::Error;
 struct VmVisitor;
 
 impl Vm {
+    /// Registers background work of `o`. Its `Event::RunUpdate`s are delivered until
+    /// `finish_running` is called.
     pub fn start_running(&mut self, o: &ObjectCell) -> u64 {
         self.last_run_id += 1;
         self.run_ids.insert(self.last_run_id, Arc::downgrade(o));
         self.last_run_id
     }
-    fn blueprint_index(&self, blueprint: &Arc<RefCell<Blueprint>>) -> u32 {
-        for (i, other) in self.blueprints.iter().enumerate() {
-            if Arc::ptr_eq(blueprint, other) {
-                return i as u32;
-            }
+    /// Marks the run as done, allowing jobs that wait for it to continue.
+    pub fn finish_running(&mut self, run_id: u64, succeeded: bool) {
+        self.run_ids.remove(&run_id);
+        if !succeeded {
+            self.failed_runs.insert(run_id);
         }
-        panic!("Bad blueprint reference");
+    }
+    pub fn running(&self, run_id: u64) -> bool {
+        self.run_ids.contains_key(&run_id)
+    }
+    /// Whether the finished run failed. Forgets the failure.
+    pub fn take_failure(&mut self, run_id: u64) -> bool {
+        self.failed_runs.remove(&run_id)
+    }
+    /// Latest unfinished run of `o`.
+    pub fn current_run(&self, o: &ObjectCell) -> Option<u64> {
+        self.run_ids
+            .iter()
+            .filter(|&(_, object)| object.upgrade().map_or(false, |object| Arc::ptr_eq(&object, o)))
+            .map(|(&run_id, _)| run_id)
+            .max()
+    }
+    /// Embedded Iosevka, used to measure text.
+    pub fn font(&self) -> Arc<rusttype::Font<'static>> {
+        self.font.clone()
+    }
+    pub fn blueprint(&self, id: Id) -> Option<Arc<RefCell<Blueprint>>> {
+        self.blueprints
+            .iter()
+            .find(|blueprint| blueprint.borrow().id == id)
+            .cloned()
     }
     fn mouse_object(&self) -> Option<Weak<RefCell<Object>>> {
         let world_point = self.mouse_world();
@@ -159,21 +240,25 @@ impl Vm {
         let frame_rc = frame_rc.unwrap();
         return Some(Arc::downgrade(&blueprint.get_object(&frame_rc)));
     }
+    pub fn session(&self) -> &Session {
+        &self.sessions[&self.client]
+    }
+    pub fn session_mut(&mut self) -> &mut Session {
+        self.sessions.get_mut(&self.client).unwrap()
+    }
     fn mouse_display(&self) -> DisplayPoint {
-        self.display.to_millimetre(self.mouse)
+        self.session().mouse_display()
     }
     fn mouse_world(&self) -> WorldPoint {
-        self.display.to_millimetre(self.mouse) * self.zoom - *self.center.borrow()
+        self.session().mouse_world()
     }
     pub fn new() -> Arc<RefCell<Vm>> {
-        let font_collection = rusttype::FontCollection::from_bytes(FONT);
-        let font = Arc::new(font_collection.into_font().unwrap());
-
-        let (tx, rx) = mpsc::channel();
+        let vm = Vm::new_headless();
 
         http::start_thread();
+        quit_on_signals(vm.borrow().tx.clone());
 
-        let websocket_tx = tx.clone();
+        let websocket_tx = vm.borrow().tx.clone();
         thread::spawn(move || {
             let server = websocket::Server::bind("127.0.0.1:8081").unwrap();
             for stream in server
@@ -187,81 +272,288 @@ impl Vm {
             }
         });
 
-        Arc::new(RefCell::new(Vm {
+        vm
+    }
+
+    /// Creates a VM without starting the HTTP & websocket servers.
+    pub fn new_headless() -> Arc<RefCell<Vm>> {
+        let font_collection = rusttype::FontCollection::from_bytes(FONT);
+        let font = Arc::new(font_collection.into_font().unwrap());
+
+        let (tx, rx) = mpsc::channel();
+
+        let vm = Arc::new(RefCell::new(Vm {
+            this: Weak::new(),
             blueprints: Vec::new(),
             active_blueprint: Weak::new(),
-            types: vec![&process_type, &text_type, &empty_type],
+            types: TypeRegistry::new(),
             tasks: VecDeque::new(),
             is_running: true,
             rx: rx,
             tx: tx,
-            center: Arc::new(RefCell::new(WorldPoint::new(0., 0.))),
-            display: Display {
-                size: PixelPoint::new(1024., 768.),
-                dpi: 96.,
-                eye_distance_meters: 0.5,
-            },
             websocket_clients: HashMap::new(),
             run_ids: HashMap::new(),
+            failed_runs: HashSet::new(),
             last_run_id: 0,
+            jobs: Vec::new(),
+            last_job_id: 0,
+            job_results: HashMap::new(),
+            batches: Vec::new(),
+            machine_concurrency: 4,
+            children: Vec::new(),
+            save_config: SaveConfig::new(),
+            last_save: time::Instant::now(),
+            last_change: None,
+            saved_state: String::new(),
             font: font,
             client_counter: 0,
-            mouse: PixelPoint::new(0., 0.),
-            last_update: time::Instant::now(),
-            mouse_handler: None,
-            menus: Vec::new(),
-            zoom: ScaleFactor::new(1.0),
-        }))
+            sessions: HashMap::new(),
+            client: 0,
+        }));
+        vm.borrow_mut().this = Arc::downgrade(&vm);
+        {
+            let types = &mut vm.borrow_mut().types;
+            types.register(Arc::new(process_type)).unwrap();
+            types.register(Arc::new(text_type)).unwrap();
+            types.register(Arc::new(empty_type)).unwrap();
+            types.register(Arc::new(parameter_type)).unwrap();
+            types.register(Arc::new(blueprint_type)).unwrap();
+        }
+        vm
     }
 
     pub fn activate(&mut self, blueprint: &Arc<RefCell<Blueprint>>) {
         self.active_blueprint = Arc::downgrade(blueprint);
     }
 
-    pub fn load_json(this: &Arc<RefCell<Vm>>) -> Result<(), Box<Error>> {
-        use std::fs::File;
-        use std::io::Read;
-        let file = File::open("vm.json")?;
+    /// Adds a blueprint with a single machine.
+    pub fn add_blueprint(&mut self, name: String) -> Arc<RefCell<Blueprint>> {
+        let blueprint = Blueprint::detached(new_id(), self.this.clone());
+        blueprint.borrow_mut().rename(name);
+        self.blueprints.push(blueprint.clone());
+        let machine = Machine::new(&blueprint);
+        blueprint.borrow_mut().activate(&machine);
+        blueprint
+    }
+
+    /// Removes `blueprint` together with its machines.
+    ///
+    /// The last blueprint and blueprints instantiated by other blueprints can't be removed.
+    pub fn remove_blueprint(
+        &mut self,
+        blueprint: &Arc<RefCell<Blueprint>>,
+    ) -> Result<(), &'static str> {
+        if self.blueprints.len() < 2 {
+            return Err("Can't delete the last blueprint");
+        }
+        let users = self.blueprints.iter().filter(|other| {
+            !Arc::ptr_eq(other, blueprint) && other.borrow().uses(blueprint)
+        });
+        if users.count() > 0 {
+            return Err("Can't delete a blueprint used by other blueprints");
+        }
+        let idx = match self.blueprints.iter().position(|other| Arc::ptr_eq(other, blueprint)) {
+            Some(idx) => idx,
+            None => return Err("Blueprint was already deleted"),
+        };
+        self.blueprints.remove(idx);
+        let was_active = self.active_blueprint
+            .upgrade()
+            .map_or(true, |active| Arc::ptr_eq(&active, blueprint));
+        if was_active {
+            let next = self.blueprints[idx.min(self.blueprints.len() - 1)].clone();
+            self.activate(&next);
+        }
+        Ok(())
+    }
+
+    /// Overlay corner of the current client.
+    pub fn overlay_corner(&self) -> DisplayPoint {
+        self.session().overlay_corner()
+    }
+
+    /// Loads the state saved at the configured save path.
+    pub fn load_json(this: &Arc<RefCell<Vm>>) -> LoadResult<()> {
+        let path = this.borrow().save_config.path.clone();
+        Vm::load_file(this, &path)
+    }
+
+    pub fn load_file(this: &Arc<RefCell<Vm>>, path: &std::path::Path) -> LoadResult<()> {
+        let file = std::fs::File::open(path)?;
         let value: serde_json::Value = serde_json::from_reader(file)?;
-        let blueprints = value.get("blueprints").ok_or("No blueprints")?;
-        let blueprints = blueprints.as_array().ok_or("Blueprints is not an array")?;
-        for blueprint in blueprints.iter() {
-            let mybp = Blueprint::new(this);
-            Blueprint::load_json(&mybp, blueprint);
-        }
-        let active_blueprint = value.get("active_blueprint").unwrap().as_i64().unwrap();
-        let weak_bp = Arc::downgrade(&this.borrow().blueprints[active_blueprint as usize]);
-        this.borrow_mut().active_blueprint = weak_bp;
-        let tasks = value.get("tasks").ok_or("No tasks")?;
-        let tasks = tasks.as_array().ok_or("Tasks is not an array")?;
-        for task in tasks.iter() {
-            // TODO
-        }
-        let mut contents = String::new();
-        let mut file = File::open("vm.json")?;
-        file.read_to_string(&mut contents)?;
-        println!("File contents:");
-        println!("{}", contents);
-        use std::ops::Deref;
-        let buffer = serde_json::to_string(this.borrow().deref()).ok().unwrap();
-        println!("Loaded contents:");
-        println!("{}", buffer);
+        Vm::load_value(this, value)
+    }
 
+    pub fn load_value(this: &Arc<RefCell<Vm>>, mut value: serde_json::Value) -> LoadResult<()> {
+        migrate(&mut value)?;
+        let value = &value;
+        let blueprints = get_array(value, "blueprints")?;
+        let mut loaded = Vec::new();
+        for (i, blueprint) in blueprints.iter().enumerate() {
+            let id = get_id(blueprint, "id").at(Location::Blueprint(i))?;
+            if this.borrow().blueprint(id).is_some() {
+                return Err(duplicate_id(id).at(Location::Field("id")).at(Location::Blueprint(i)));
+            }
+            loaded.push(Blueprint::with_id(id, this));
+        }
+        for (i, blueprint) in blueprints.iter().enumerate() {
+            Blueprint::load_frames_json(&loaded[i], blueprint).at(Location::Blueprint(i))?;
+        }
+        for (i, blueprint) in loaded.iter().enumerate() {
+            if blueprint.borrow().uses(blueprint) {
+                return Err(
+                    LoadError::new(LoadErrorKind::BadData(
+                        "blueprint contains itself".to_string(),
+                    )).at(Location::Blueprint(i)),
+                );
+            }
+        }
+        for (i, blueprint) in blueprints.iter().enumerate() {
+            Blueprint::load_json(&loaded[i], blueprint).at(Location::Blueprint(i))?;
+        }
+        let active_blueprint = get_id(value, "active_blueprint")?;
+        let active_blueprint = this.borrow().blueprint(active_blueprint).ok_or_else(|| {
+            LoadError::new(LoadErrorKind::UnknownId(active_blueprint))
+                .at(Location::Field("active_blueprint"))
+        })?;
+        this.borrow_mut().active_blueprint = Arc::downgrade(&active_blueprint);
+        let tasks = get_array(value, "tasks")?;
+        for (i, task) in tasks.iter().enumerate() {
+            let object = Vm::load_task_json(this, task).at(Location::Task(i))?;
+            this.borrow_mut().tasks.push_back(Arc::downgrade(&object));
+        }
         Ok(())
     }
 
+    fn load_task_json(this: &Arc<RefCell<Vm>>, task: &serde_json::Value) -> LoadResult<ObjectCell> {
+        let task = task.as_array().ok_or_else(|| {
+            LoadError::new(LoadErrorKind::WrongType("an array"))
+        })?;
+        if task.len() != 3 {
+            return Err(LoadError::new(LoadErrorKind::WrongType(
+                "a (blueprint, frame, machine) tuple",
+            )));
+        }
+        let blueprint_rc = as_id(&task[0])
+            .and_then(|id| {
+                this.borrow().blueprint(id).ok_or_else(|| {
+                    LoadError::new(LoadErrorKind::UnknownId(id))
+                })
+            })
+            .at(Location::Field("blueprint"))?;
+        let blueprint = blueprint_rc.borrow();
+        let frame_rc = as_id(&task[1])
+            .and_then(|id| find_frame(&blueprint, id))
+            .at(Location::Field("frame"))?;
+        let machine_rc = as_id(&task[2])
+            .and_then(|id| {
+                blueprint.machine(id).ok_or_else(|| {
+                    LoadError::new(LoadErrorKind::UnknownId(id))
+                })
+            })
+            .at(Location::Field("machine"))?;
+        let machine = machine_rc.borrow();
+        machine.find_object(&frame_rc).ok_or_else(|| {
+            LoadError::new(LoadErrorKind::BadData(format!(
+                "machine {} has no object for frame {}",
+                machine.id,
+                frame_rc.borrow().id
+            )))
+        })
+    }
+
+    /// Sends every client a rendering of its own view - right away, or once it's done drawing
+    /// the previous one.
     fn update_clients(&mut self) {
-        let mut c = JsonCanvas::new(self.font.clone());
-        self.draw(&mut c);
-        let json = c.serialize();
-        let message = websocket::Message::text(json);
-        for (id, writer) in &mut self.websocket_clients {
+        let clients: Vec<i64> = self.sessions.keys().cloned().collect();
+        for client in clients {
+            self.sessions.get_mut(&client).unwrap().pending = true;
+            self.flush(client);
+        }
+    }
+
+    /// Shows the pointer of the current client to the others - only their `Remote` layer changes.
+    fn update_other_clients(&mut self) {
+        let current = self.client;
+        let clients: Vec<i64> = self.sessions.keys().cloned().filter(|&c| c != current).collect();
+        for client in clients {
+            self.sessions.get_mut(&client).unwrap().pending = true;
+            self.flush(client);
+        }
+    }
+
+    /// Sends renderings held back for clients whose last one timed out.
+    fn flush_expired(&mut self) {
+        let clients: Vec<i64> = self.sessions
+            .iter()
+            .filter(|&(_, session)| session.pending)
+            .map(|(&client, _)| client)
+            .collect();
+        for client in clients {
+            self.flush(client);
+        }
+    }
+
+    /// How long the main loop may wait for events before a held back rendering times out.
+    fn flush_timeout(&self) -> Option<time::Duration> {
+        self.sessions
+            .values()
+            .filter(|session| session.pending)
+            .map(|session| {
+                frame_timeout()
+                    .checked_sub(session.last_update.elapsed())
+                    .unwrap_or(time::Duration::from_secs(0))
+            })
+            .min()
+    }
+
+    /// Sends the layers of `client` that changed since its last rendering.
+    fn flush(&mut self, client: i64) {
+        {
+            let session = &self.sessions[&client];
+            let busy = session.frame_state != FrameState::Idle &&
+                session.last_update.elapsed() < frame_timeout();
+            if !session.pending || busy {
+                return;
+            }
+        }
+        let current = self.client;
+        self.client = client;
+        let protocol = self.session().protocol;
+        let layers = LAYERS
+            .iter()
+            .map(|&layer| match protocol {
+                Protocol::Json => {
+                    let mut c = JsonCanvas::new(self.font.clone());
+                    self.draw(layer, &mut c);
+                    c.serialize().into_bytes()
+                }
+                Protocol::Binary => {
+                    let mut c = BinaryCanvas::new(self.font.clone());
+                    self.draw(layer, &mut c);
+                    c.into_bytes()
+                }
+            })
+            .collect();
+        self.client = current;
+        let session = self.sessions.get_mut(&client).unwrap();
+        session.pending = false;
+        let changed = diff_layers(&mut session.layers, layers);
+        if changed.is_empty() {
+            return;
+        }
+        let message = match protocol {
+            Protocol::Json => websocket::Message::text(layers_message(&changed)),
+            Protocol::Binary => websocket::Message::binary(binary_layers_message(&changed)),
+        };
+        if let Some(writer) = self.websocket_clients.get_mut(&client) {
             writer.send_message(&message);
         }
-        self.last_update = time::Instant::now();
+        session.frame_state = FrameState::Sent;
+        session.last_update = time::Instant::now();
     }
 
-    fn draw(&mut self, c: &mut Canvas) {
+    fn draw(&mut self, layer: Layer, c: &mut Canvas) {
         let blueprint_rc = self.active_blueprint.upgrade().unwrap();
         let blueprint = blueprint_rc.borrow();
 
@@ -276,40 +568,35 @@ impl Vm {
 
         c.save();
         c.font(format!("{}px Iosevka", 6.).as_str());
-        c.translate(self.display.size.x / 2., self.display.size.y / 2.);
-        c.scale(self.display.pixel_size().inv().get());
-
-        c.save();
-        c.scale(self.zoom.inv().get());
         {
-            let center = self.center.borrow();
-            c.translate(center.x, center.y);
+            let session = self.session();
+            let display = &session.display;
+            c.translate(display.size.x / 2., display.size.y / 2.);
+            c.scale(display.pixel_size().inv().get());
+            match layer {
+                Layer::Frames | Layer::Links | Layer::Remote => {
+                    c.scale(session.zoom.inv().get());
+                    let center = session.center.borrow();
+                    c.translate(center.x, center.y);
+                }
+                Layer::Overlay | Layer::Menus => (),
+            }
         }
-        draw(&blueprint.frames, c);
-        draw(&blueprint.links, c);
-        c.restore();
-
-        use PARAM_RADIUS;
-        let pixel_scale = self.display.pixel_size().get();
-        let half_width = self.display.size.x * 0.5 * pixel_scale;
-        let half_height = self.display.size.y * 0.5 * pixel_scale;
-        let mut top = -half_height + PARAM_RADIUS * 2.;
-        let left = -half_width + PARAM_RADIUS * 2.;
-        let active_machine = blueprint.active_machine.upgrade().unwrap();
-        for machine in blueprint.machines.iter() {
-            let fill_style = if Arc::ptr_eq(machine, &active_machine) {
-                "#3e64a3"
-            } else {
-                "#959ba5"
-            };
-            c.fillStyle(fill_style);
-            c.fillCircle(left, top, PARAM_RADIUS);
-            top += PARAM_RADIUS * 3.;
+        match layer {
+            Layer::Frames => draw(&blueprint.frames, c),
+            Layer::Links => draw(&blueprint.links, c),
+            Layer::Remote => draw_remote_touches(self, c),
+            Layer::Overlay => {
+                draw_machine_list(self, c);
+                draw_blueprint_list(self, c);
+                draw_presence(self, c);
+            }
+            Layer::Menus => {
+                let menus_rc = self.session().menus.iter().filter_map(|x| x.upgrade()).collect();
+                draw(&menus_rc, c);
+                self.session_mut().menus = menus_rc.iter().map(Arc::downgrade).collect();
+            }
         }
-
-        let menus_rc = self.menus.iter().filter_map(|x| x.upgrade()).collect();
-        draw(&menus_rc, c);
-        self.menus = menus_rc.iter().map(Arc::downgrade).collect();
         c.restore();
     }
 
@@ -320,21 +607,39 @@ impl Vm {
             name: "Move view".to_string(),
             color: None,
             shortcuts: vec!["MMB".to_string()],
-            action: Box::new(MovePointAction::new(Arc::downgrade(&self.center), true)),
+            action: Box::new(MovePointAction::new(Arc::downgrade(&self.session().center), true)),
         };
-        let type_entries = self.types.iter().map(|typ| {
-            Entry {
-                name: format!("New {}", typ.name),
-                color: None,
-                shortcuts: Vec::new(),
-                action: Box::new(AddFrameAction::new(typ)),
-            }
-        });
+        // Instances are placed from the blueprint list, which knows what they instantiate.
+        let type_entries = self.types
+            .iter()
+            .filter(|typ| typ.name() != blueprint_type.name)
+            .map(|typ| {
+                Entry {
+                    name: format!("New {}", typ.name()),
+                    color: None,
+                    shortcuts: Vec::new(),
+                    action: Box::new(AddFrameAction::new(typ.clone())),
+                }
+            });
+
+        if let Some(blueprint) = blueprint_at(self, d) {
+            return blueprint_menu(&blueprint);
+        }
+
+        if let Some(machine) = machine_at(self, d) {
+            return machine_menu(&machine);
+        }
 
         {
             let blueprint = self.active_blueprint.upgrade().unwrap();
             let blueprint = blueprint.borrow();
             let frames = blueprint.frames.clone();
+            let links = blueprint.links.clone();
+
+            if let Some(mut link_menu) = walk_visible(&links, |link| link.make_menu(d, w)) {
+                link_menu.entries.push(move_view);
+                return link_menu;
+            }
 
             if let Some(mut frame_menu) = walk_visible(&frames, |frame| frame.make_menu(d, w)) {
                 frame_menu.entries.push(move_view);
@@ -345,6 +650,7 @@ impl Vm {
 
         let mut menu_entries = vec![move_view];
         menu_entries.extend(type_entries);
+        menu_entries.extend(history_entries());
 
         Menu {
             entries: menu_entries,
@@ -368,7 +674,7 @@ impl Vm {
 
     fn open_menu(&mut self, menu: Menu, point: DisplayPoint) -> Option<Box<TouchReceiver>> {
         let visible_menu_rc = VisibleMenu::new(menu, point);
-        self.menus.push(Arc::downgrade(&visible_menu_rc));
+        self.session_mut().menus.push(Arc::downgrade(&visible_menu_rc));
         Some(Box::new(visible_menu_rc))
     }
 
@@ -382,6 +688,11 @@ impl Vm {
         match event {
             Event::Quit(mut over) => {
                 println!("VM: received Quit");
+                kill_all(&mut self.children);
+                if let Err(err) = self.save() {
+                    println!("Couldn't save VM state: {}", err);
+                }
+                self.is_running = false;
                 over.send(0).unwrap();
                 println!("VM: sent response");
             }
@@ -394,6 +705,8 @@ impl Vm {
                     client_number,
                     websocket_writer,
                 );
+                self.sessions.insert(client_number, Session::new());
+                self.update_clients();
                 let websocket_tx = self.tx.clone();
                 thread::spawn(move || {
                     for message in websocket_reader.incoming_messages() {
@@ -410,8 +723,8 @@ impl Vm {
                                 let payload = message.payload.to_mut();
                                 let json: serde_json::Value = serde_json::from_slice(payload)
                                     .unwrap();
-                                Event::from(json).map(|event| {
-                                    websocket_tx.send(event).unwrap();
+                                ClientEvent::from(json).map(|event| {
+                                    websocket_tx.send(Event::Client(client_number, event)).unwrap();
                                 });
                             }
                             _ => {}
@@ -425,8 +738,55 @@ impl Vm {
             Event::WebsocketDisconnected(i) => {
                 println!("Client {} disconnected", i);
                 self.websocket_clients.remove(&i);
+                // Gestures in progress are finished, so they don't leave open transactions or
+                // dangling links behind.
+                let taken = self.sessions.get_mut(&i).and_then(|s| s.mouse_handler.take());
+                if let Some(touch_receiver) = taken {
+                    self.client = i;
+                    touch_receiver.end_touch(self);
+                    self.mark_dirty();
+                }
+                self.sessions.remove(&i);
+                self.update_clients();
+            }
+            Event::Client(client, event) => {
+                // Events may still arrive from a client that has just disconnected.
+                if !self.sessions.contains_key(&client) {
+                    return;
+                }
+                self.client = client;
+                self.process_client_event(event);
             }
-            Event::MouseDown {
+            Event::Closure(mut closure) => {
+                closure();
+                self.mark_dirty();
+            }
+            Event::RegisterType(typ) => {
+                let name = typ.name().to_string();
+                match self.types.register(typ) {
+                    Ok(_) => println!("Registered type {}", name),
+                    Err(err) => println!("{}", err),
+                }
+            }
+            Event::RunUpdate(run_id, arg) => {
+                // The object is gone if its frame or blueprint was deleted while it was running.
+                let object = match self.run_ids.get(&run_id).and_then(Weak::upgrade) {
+                    Some(object) => object,
+                    None => return,
+                };
+                let typ = object.borrow().typ();
+                typ.update(self, &object, arg);
+                self.advance_jobs();
+                self.mark_dirty();
+                self.update_clients();
+            }
+            _ => {}
+        }
+    }
+
+    fn process_client_event(&mut self, event: ClientEvent) {
+        match event {
+            ClientEvent::MouseDown {
                 x: x,
                 y: y,
                 button: button,
@@ -472,13 +832,13 @@ impl Vm {
                  * Activating an action moves the interaction to the world space.
                  */
 
-                if self.mouse_handler.is_some() {
+                if self.session().mouse_handler.is_some() {
                     return;
                 }
                 let display_point = self.mouse_display();
                 let world_point = self.mouse_world();
                 let menu = self.make_menu();
-                self.mouse_handler = match button {
+                let mouse_handler = match button {
                     0 => {
                         menu.activate_shortcut(self, "LMB".to_string(), display_point, world_point)
                     }
@@ -488,52 +848,65 @@ impl Vm {
                     2 => self.open_menu(menu, display_point),
                     _ => None,
                 };
+                self.session_mut().mouse_handler = mouse_handler;
+                self.mark_dirty();
                 self.update_clients();
             }
-            Event::MouseUp {
+            ClientEvent::MouseUp {
                 x: x,
                 y: y,
                 button: button,
             } => {
-                match self.mouse_handler.take() {
+                match self.session_mut().mouse_handler.take() {
                     Some(touch_receiver) => touch_receiver.end_touch(self),
                     None => (),
                 }
+                self.mark_dirty();
                 self.update_clients();
             }
-            Event::MouseMove { x: x, y: y } => {
-                self.mouse = PixelPoint::new(x, y);
+            ClientEvent::MouseMove { x: x, y: y } => {
+                self.session_mut().mouse = PixelPoint::new(x, y);
                 let display = self.mouse_display();
                 let world = self.mouse_world();
 
-                let taken = self.mouse_handler.take();
+                let taken = self.session_mut().mouse_handler.take();
                 let update = taken.is_some();
                 let taken = taken.and_then(|b| b.continue_touch(self, display, world));
-                self.mouse_handler = taken;
+                self.session_mut().mouse_handler = taken;
 
                 if update {
+                    self.mark_dirty();
                     self.update_clients();
+                } else {
+                    self.update_other_clients();
                 }
             }
-            Event::KeyDown {
+            ClientEvent::KeyDown {
                 code: code,
                 key: key,
+                ctrl: ctrl,
+                shift: shift,
             } => {
                 println!("Pressed key {}, code {}", key, code);
                 if code == "PrintScreen" {
-                    use std::fs::File;
-                    use std::io::Write;
-                    let mut file = File::create("vm.json").ok().unwrap();
-                    let buffer = serde_json::to_string(self).ok().unwrap();
-                    file.write_all(buffer.as_ref()).ok().unwrap();
-                    println!("VM state saved");
+                    match self.save() {
+                        Ok(_) => println!("VM state saved"),
+                        Err(err) => println!("Couldn't save VM state: {}", err),
+                    }
+                    return;
+                }
+                self.mark_dirty();
+                if self.session().renaming.is_some() {
+                    rename_key(self, &code, &key);
+                    self.update_clients();
                     return;
                 }
-                if code == "Insert" {
+                let shortcut = shortcut_name(&code, ctrl, shift);
+                if shortcut == "Insert" {
                     use Machine;
                     let machine = Machine::new(&self.active_blueprint.upgrade().unwrap());
                 }
-                if code == "Delete" {
+                if shortcut == "Delete" {
                     let bp = self.active_blueprint.upgrade().unwrap();
                     let mut bp = bp.borrow_mut();
                     let mc = bp.active_machine.upgrade().unwrap();
@@ -544,14 +917,16 @@ impl Vm {
                         .unwrap()
                         .0;
                     if idx > 0 {
-                        bp.machines.remove(idx);
+                        let removed = bp.machines.remove(idx);
+                        let id = removed.borrow().id;
+                        bp.machines_by_id.remove(&id);
                         if idx >= bp.machines.len() {
                             idx -= 1;
                         }
                         bp.active_machine = Arc::downgrade(&bp.machines[idx]);
                     }
                 }
-                if code == "PageDown" || code == "PageUp" {
+                if shortcut == "PageDown" || shortcut == "PageUp" {
                     let bp = self.active_blueprint.upgrade().unwrap();
                     let mut bp = bp.borrow_mut();
                     let mc = bp.active_machine.upgrade().unwrap();
@@ -569,102 +944,141 @@ impl Vm {
                     let idx = (idx + delta) % bp.machines.len();
                     bp.active_machine = Arc::downgrade(&bp.machines[idx]);
                 }
-                if self.mouse_handler.is_some() {
+                if self.session().mouse_handler.is_some() {
+                    return;
+                }
+                let mut menu = self.make_menu();
+                menu.entries.extend(history_entries());
+                menu.entries.extend(blueprint_entries(self));
+                let mouse_handler = self.activate_shortcut(menu, shortcut);
+                self.session_mut().mouse_handler = mouse_handler;
+                if ctrl || self.session().renaming.is_some() {
+                    self.update_clients();
                     return;
                 }
-                let menu = self.make_menu();
-                self.mouse_handler = match code.as_ref() {
-                    "Delete" => self.activate_shortcut(menu, code.clone()),
-                    "Space" => self.activate_shortcut(menu, code.clone()),
-                    _ => None,
-                };
                 if let Some(weak) = self.mouse_object() {
                     let rc = weak.upgrade().unwrap();
+                    let frame = rc.borrow().frame.clone();
+                    if !frame.borrow().typ.editable() {
+                        self.update_clients();
+                        return;
+                    }
+                    if let Err(err) = self.check_editable(&frame) {
+                        println!("{}", err);
+                        self.update_clients();
+                        return;
+                    }
+                    let mut edit = None;
                     {
                         let mut object = rc.borrow_mut();
-                        if ref_eq::ref_eq(object.frame.borrow().typ, &text_type) {
+                        if let Some(contents) = object.data.downcast_mut::<String>() {
+                            let before = contents.clone();
                             if key.len() == 1 {
-                                let mut contents = object.data.downcast_mut::<String>().unwrap();
                                 contents.push_str(key.as_ref());
                             } else if key == "Backspace" {
-                                let mut contents = object.data.downcast_mut::<String>().unwrap();
                                 contents.pop();
                             }
+                            if *contents != before {
+                                edit = Some(Edit::SetText {
+                                    object: rc.clone(),
+                                    before: before,
+                                    after: contents.clone(),
+                                });
+                            }
+                        }
+                    }
+                    if let Some(edit) = edit {
+                        let blueprint = rc.borrow().frame.borrow().blueprint.upgrade();
+                        if let Some(blueprint) = blueprint {
+                            blueprint.borrow_mut().history.record(self.client, edit);
                         }
                     }
                 }
                 self.update_clients();
             }
-            Event::DisplaySize {
+            ClientEvent::DisplaySize {
                 width: w,
                 height: h,
+                dpi: dpi,
             } => {
-                self.display.size = PixelPoint::new(w, h);
-                println!("Display size is {} x {} px", w, h);
+                {
+                    let display = &mut self.session_mut().display;
+                    display.size = PixelPoint::new(w, h);
+                    display.dpi = dpi;
+                }
+                println!("Client {} display is {} x {} px at {} dpi", self.client, w, h, dpi);
                 self.update_clients();
             }
-            Event::RenderingDone => {
-                /*
-                    let dur = last_update.elapsed();
-                    println!("Rendering done ({} ms)",
-                    (dur.as_secs() as f64) * 1000. + (dur.subsec_nanos() as f64) / 1000000.);
-                     */
-            }
-            Event::RenderingReady => {
-                /*
-                let dur = last_update.elapsed();
-                println!("Rendering ready ({} ms)",
-                         (dur.as_secs() as f64) * 1000. + (dur.subsec_nanos() as f64) / 1000000.);
-                 */
+            ClientEvent::Protocol { binary: binary } => {
+                {
+                    let session = self.session_mut();
+                    session.protocol = if binary {
+                        Protocol::Binary
+                    } else {
+                        Protocol::Json
+                    };
+                    // Layers sent in the previous encoding are replaced entirely.
+                    session.layers.clear();
+                    session.frame_state = FrameState::Idle;
+                    session.pending = true;
+                }
+                let client = self.client;
+                self.flush(client);
             }
-            Event::MouseWheel { x: x, y: y } => {
+            ClientEvent::RenderingDone => {
+                let session = self.session_mut();
+                if session.frame_state == FrameState::Sent {
+                    session.frame_state = FrameState::Drawn;
+                }
+            }
+            ClientEvent::RenderingReady => {
+                // Changes made while the client was drawing are sent as a single rendering.
+                if self.session().frame_state == FrameState::Drawn {
+                    self.session_mut().frame_state = FrameState::Idle;
+                    let client = self.client;
+                    self.flush(client);
+                }
+            }
+            ClientEvent::MouseWheel { x: x, y: y } => {
                 {
                     let start = self.mouse_world();
-                    self.zoom = ScaleFactor::new(self.zoom.get() * (y / 200.).exp());
-                    let end = self.mouse_world();
-                    let mut center = self.center.borrow_mut();
+                    let session = self.session_mut();
+                    session.zoom = ScaleFactor::new(session.zoom.get() * (y / 200.).exp());
+                    let end = session.mouse_world();
+                    let mut center = session.center.borrow_mut();
                     *center = *center - start + end;
                 }
                 self.update_clients();
             }
-            Event::Closure(mut closure) => {
-                closure();
-            }
-            Event::RunUpdate(run_id, arg) => {
-                let object = {
-                    let ref weak = self.run_ids[&run_id];
-                    weak.upgrade().unwrap()
-                };
-                let typ = object.borrow().typ();
-                (typ.update.unwrap())(self, &object, arg);
-            }
             _ => {}
         }
     }
 
-    fn collect_args(&self, object: &ObjectCell) -> RunArgs {
+    pub fn collect_args(&self, object: &ObjectCell) -> RunArgs {
         let object = object.borrow();
         let machine_rc = object.machine.upgrade().unwrap();
-        let machine = machine_rc.borrow_mut();
+        let machine = machine_rc.borrow();
         let frame = object.frame.borrow();
         let mut args = vec![];
-        for param in frame.typ.parameters {
+        for param in frame.parameters().iter() {
             args.push(vec![]);
         }
         let blueprint_rc = frame.blueprint.upgrade().unwrap();
         let blueprint = blueprint_rc.borrow();
-        for link_rc in blueprint.links.iter() {
-            let link = link_rc.borrow();
-            if let &LinkTerminator::FrameParam(FrameParam {
-                                                   frame: ref frame_a,
-                                                   param_index: param_index,
-                                               }) = &link.a
-            {
-                if !Arc::ptr_eq(frame_a, &object.frame) {
-                    continue;
-                }
+        for (param_index, arg) in args.iter_mut().enumerate() {
+            let frame_param = FrameParam {
+                frame: object.frame.clone(),
+                param_index: param_index,
+            };
+            for link_rc in blueprint.param_links(&frame_param) {
+                let link = link_rc.borrow();
                 if let &LinkTerminator::Frame(ref frame_b) = &link.b {
-                    args[param_index].push(machine.get_object(frame_b));
+                    let frame_id = frame_b.borrow().id;
+                    if let Some(nested_arg) = machine.arguments.get(&frame_id) {
+                        arg.extend(nested_arg.iter().cloned());
+                    } else if let Some(object) = machine.object_for(frame_b) {
+                        arg.push(object);
+                    }
                 }
             }
         }
@@ -673,28 +1087,430 @@ impl Vm {
 
     fn process_task(&mut self, object: Weak<RefCell<Object>>) {
         if let Some(object_rc) = object.upgrade() {
-            let args = self.collect_args(&object_rc);
-            let typ = {
-                let object = object_rc.borrow();
-                let typ = object.frame.borrow().typ;
-                typ
-            };
-            (typ.run)(self, &object_rc, args);
+            self.schedule(&object_rc);
+        }
+    }
+
+    /// Runs `object` with its current arguments, ignoring its dependencies.
+    pub fn run_object(&mut self, object_rc: &ObjectCell) {
+        let args = self.collect_args(object_rc);
+        let typ = {
+            let object = object_rc.borrow();
+            let typ = object.frame.borrow().typ.clone();
+            typ
+        };
+        typ.run(self, object_rc, args);
+    }
+
+    pub fn mark_dirty(&mut self) {
+        self.last_change = Some(time::Instant::now());
+    }
+
+    /// Writes the VM state to the configured save path.
+    pub fn save(&mut self) -> io::Result<()> {
+        let buffer = serde_json::to_string(self)?;
+        self.write_state(buffer)
+    }
+
+    fn write_state(&mut self, buffer: String) -> io::Result<()> {
+        self.save_config.write(buffer.as_ref())?;
+        self.last_change = None;
+        self.last_save = time::Instant::now();
+        self.saved_state = buffer;
+        Ok(())
+    }
+
+    /// How long the main loop may wait for events before an autosave is due.
+    fn autosave_timeout(&self) -> time::Duration {
+        let zero = time::Duration::from_secs(0);
+        let periodic = self.save_config
+            .interval
+            .checked_sub(self.last_save.elapsed())
+            .unwrap_or(zero);
+        match self.last_change {
+            Some(last_change) => {
+                let delayed = self.save_config
+                    .delay
+                    .checked_sub(last_change.elapsed())
+                    .unwrap_or(zero);
+                delayed.min(periodic)
+            }
+            None => periodic,
+        }
+    }
+
+    fn autosave(&mut self) {
+        if self.autosave_timeout() > time::Duration::from_secs(0) {
+            return;
+        }
+        let buffer = match serde_json::to_string(self) {
+            Ok(buffer) => buffer,
+            Err(err) => {
+                println!("Autosave failed: {}", err);
+                self.last_save = time::Instant::now();
+                return;
+            }
+        };
+        // Writing the same state again would rotate the backups until they're all identical.
+        if self.last_change.is_none() && buffer == self.saved_state {
+            self.last_save = time::Instant::now();
+            return;
+        }
+        match self.write_state(buffer) {
+            Ok(_) => println!("VM state autosaved"),
+            Err(err) => {
+                println!("Autosave failed: {}", err);
+                self.last_save = time::Instant::now();
+            }
+        }
+    }
+
+    /// Processes events until `done` returns true. Panics if that takes more than 10 seconds.
+    #[cfg(test)]
+    pub fn run_until<F: Fn(&Vm) -> bool>(&mut self, done: F) {
+        let deadline = time::Instant::now() + time::Duration::from_secs(10);
+        while !done(self) {
+            assert!(time::Instant::now() < deadline, "timed out waiting for events");
+            let event = self.rx.recv_timeout(time::Duration::from_secs(1)).unwrap();
+            self.process_event(event);
         }
     }
 
     pub fn run(&mut self) {
         while self.is_running {
+            self.autosave();
+            self.flush_expired();
             if let Ok(event) = self.rx.try_recv() {
                 self.process_event(event);
             } else if let Some(task) = self.tasks.pop_front() {
                 self.process_task(task);
-            } else if let Ok(event) = self.rx.recv() {
-                self.process_event(event);
+                self.mark_dirty();
             } else {
-                println!("MAIN: Breaking main loop");
-                break;
+                let mut timeout = self.autosave_timeout();
+                if let Some(flush_timeout) = self.flush_timeout() {
+                    timeout = timeout.min(flush_timeout);
+                }
+                match self.rx.recv_timeout(timeout) {
+                    Ok(event) => self.process_event(event),
+                    Err(mpsc::RecvTimeoutError::Timeout) => (),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        println!("MAIN: Breaking main loop");
+                        break;
+                    }
+                }
+            }
+        }
+        kill_all(&mut self.children);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Deref;
+    use std::sync::Arc;
+    use std::cell::RefCell;
+    use super::*;
+    use fixtures;
+    use fixtures::{link, param};
+    use machine::Machine;
+    use Frame;
+    use ReorderLinkAction;
+    use ConnectParamAction;
+    use menu::Action;
+    use WorldSize;
+    use id::new_id;
+
+    fn set_text(machine: &Arc<RefCell<Machine>>, frame: &Arc<RefCell<Frame>>, text: &str) {
+        let object = machine.borrow().get_object(frame);
+        object.borrow_mut().data = Box::new(text.to_string());
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let vm = Vm::new_headless();
+
+        let empty = Blueprint::new(&vm);
+        empty.borrow_mut().rename("Empty".to_string());
+        Machine::new(&empty);
+        let empty_machine = Machine::new(&empty);
+        empty.borrow_mut().activate(&empty_machine);
+        fixtures::frame(&empty, "Empty", true);
+
+        let blueprint = Blueprint::new(&vm);
+        blueprint.borrow_mut().rename("Main".to_string());
+        vm.borrow_mut().activate(&blueprint);
+        let first = Machine::new(&blueprint);
+        let command = fixtures::frame(&blueprint, "Text", true);
+        let process = fixtures::frame(&blueprint, "Process", false);
+        let argument = fixtures::frame(&blueprint, "Text", false);
+        {
+            let mut frame = argument.borrow_mut();
+            frame.pos = WorldPoint::new(-20.5, 30.);
+            frame.size = WorldSize::new(40., 12.);
+        }
+        let second = Machine::new(&blueprint);
+        blueprint.borrow_mut().activate(&second);
+        set_text(&first, &command, "ls");
+        set_text(&first, &argument, "-l");
+        set_text(&second, &argument, "-a");
+
+        link(&blueprint, param(&process, 0), LinkTerminator::Frame(command.clone()), 0);
+        link(&blueprint, param(&process, 1), LinkTerminator::Frame(argument.clone()), 2);
+        let point = LinkTerminator::Point(WorldPoint::new(5., -7.25));
+        link(&blueprint, param(&process, 3), point, 0);
+        link(
+            &blueprint,
+            LinkTerminator::Frame(argument.clone()),
+            LinkTerminator::Frame(command.clone()),
+            -1,
+        );
+
+        let task = second.borrow().get_object(&process);
+        vm.borrow_mut().tasks.push_back(Arc::downgrade(&task));
+
+        let saved = serde_json::to_value(vm.borrow().deref()).unwrap();
+        let reloaded = Vm::new_headless();
+        Vm::load_value(&reloaded, saved.clone()).unwrap();
+        let resaved = serde_json::to_value(reloaded.borrow().deref()).unwrap();
+        assert_eq!(saved, resaved);
+    }
+
+    #[test]
+    fn duplicate_ids_are_rejected() {
+        let (vm, blueprint) = fixtures::blueprint("Duplicates");
+        Machine::new(&blueprint);
+        let a = fixtures::frame(&blueprint, "Empty", false);
+        let b = fixtures::frame(&blueprint, "Empty", false);
+        link(&blueprint, LinkTerminator::Frame(a.clone()), LinkTerminator::Frame(b.clone()), 0);
+        link(&blueprint, LinkTerminator::Frame(b), LinkTerminator::Frame(a), 0);
+        let saved = serde_json::to_value(vm.borrow().deref()).unwrap();
+        Vm::load_value(&Vm::new_headless(), saved.clone()).unwrap();
+
+        let collections = [("frames", "frame 1"), ("links", "link 1"), ("machines", "machine 1")];
+        for &(collection, location) in collections.iter() {
+            let mut value = saved.clone();
+            {
+                let elements = value["blueprints"][0][collection].as_array_mut().unwrap();
+                elements[1]["id"] = elements[0]["id"].clone();
             }
+            let err = Vm::load_value(&Vm::new_headless(), value).unwrap_err().to_string();
+            assert!(err.contains(location) && err.contains("duplicate id"), "{}", err);
         }
     }
+
+    #[test]
+    fn autosave_skips_unchanged_state() {
+        let dir = std::env::temp_dir().join(format!("autosave-{}", new_id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let vm = Vm::new_headless();
+        let mut vm = vm.borrow_mut();
+        vm.save_config.path = dir.join("vm.json");
+        vm.save_config.delay = time::Duration::from_secs(0);
+        vm.save_config.interval = time::Duration::from_secs(0);
+        let backup = dir.join("vm.json.1");
+        let blueprint = vm.add_blueprint("Saved".to_string());
+        vm.activate(&blueprint);
+
+        vm.autosave();
+        vm.autosave();
+        assert!(!backup.exists());
+        vm.add_blueprint("Changed".to_string());
+        vm.mark_dirty();
+        vm.autosave();
+        assert!(backup.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pointers_reach_other_clients() {
+        let (vm, _) = fixtures::blueprint("Pointers");
+        let mut vm = vm.borrow_mut();
+        vm.sessions.insert(0, Session::new());
+        vm.sessions.insert(1, Session::new());
+        vm.process_event(Event::Client(0, ClientEvent::MouseMove { x: 10., y: 10. }));
+        let remote = LAYERS.iter().position(|&layer| layer == Layer::Remote).unwrap();
+        let layer = String::from_utf8(vm.sessions[&1].layers[remote].clone()).unwrap();
+        assert!(layer.contains("Client 0"));
+        assert!(vm.sessions[&0].layers.is_empty());
+    }
+
+    /// A link dragged into empty space by a client that disconnects is dropped.
+    #[test]
+    fn disconnecting_ends_touches() {
+        let (vm, blueprint) = fixtures::blueprint("Disconnect");
+        let process = fixtures::frame(&blueprint, "Process", true);
+        let mut vm = vm.borrow_mut();
+        vm.sessions.insert(1, Session::new());
+        vm.client = 1;
+        let action = Box::new(ConnectParamAction::new(&FrameParam {
+            frame: process.clone(),
+            param_index: 0,
+        }));
+        let p = WorldPoint::new(100., 100.);
+        let touch = action.start(&mut vm, DisplayPoint::new(0., 0.), p);
+        vm.sessions.get_mut(&1).unwrap().mouse_handler = touch;
+        assert_eq!(blueprint.borrow().links.len(), 1);
+
+        vm.process_event(Event::WebsocketDisconnected(1));
+        assert_eq!(blueprint.borrow().links.len(), 0);
+        assert!(vm.sessions.is_empty());
+    }
+
+    /// Typing over a frame edits Text and the name held by a Parameter, but not a Process.
+    #[test]
+    fn editable_types_take_keys() {
+        let (vm, blueprint) = fixtures::blueprint("Typing");
+        let mut vm = vm.borrow_mut();
+        vm.sessions.insert(0, Session::new());
+        vm.sessions.get_mut(&0).unwrap().mouse = PixelPoint::new(512., 384.);
+        let mut typed = |typ: &str| {
+            let typ = vm.types.get(typ).unwrap();
+            let frame = Frame::new(typ, &blueprint, true);
+            vm.process_event(Event::Client(
+                0,
+                ClientEvent::KeyDown {
+                    code: "KeyA".to_string(),
+                    key: "a".to_string(),
+                    ctrl: false,
+                    shift: false,
+                },
+            ));
+            let object = blueprint.borrow().get_object(&frame);
+            blueprint.borrow_mut().remove_frame(&frame);
+            object
+        };
+        let text = |object: ObjectCell| object.borrow().data.downcast_ref::<String>().cloned();
+        assert_eq!(text(typed("Text")), Some("a".to_string()));
+        assert_eq!(text(typed("Parameter")), Some("a".to_string()));
+        assert_eq!(text(typed("Process")), None);
+    }
+
+    /// Keys of other clients don't reach a blueprint that one client is renaming.
+    #[test]
+    fn renaming_is_per_client() {
+        let (vm, blueprint) = fixtures::blueprint("Name");
+        let mut vm = vm.borrow_mut();
+        vm.sessions.insert(0, Session::new());
+        vm.sessions.insert(1, Session::new());
+        let press = |vm: &mut Vm, client: i64, code: &str, key: &str| {
+            vm.process_event(Event::Client(
+                client,
+                ClientEvent::KeyDown {
+                    code: code.to_string(),
+                    key: key.to_string(),
+                    ctrl: false,
+                    shift: false,
+                },
+            ));
+        };
+        press(&mut vm, 0, "F2", "F2");
+        press(&mut vm, 1, "KeyX", "x");
+        press(&mut vm, 1, "Escape", "Escape");
+        press(&mut vm, 0, "KeyS", "s");
+        assert!(vm.sessions[&0].renaming.is_some());
+        assert!(vm.sessions[&1].renaming.is_none());
+        press(&mut vm, 0, "Enter", "Enter");
+        assert_eq!(blueprint.borrow().name, "Names");
+    }
+
+    /// A rendering held back for a client that never drew the previous one is sent after a while.
+    #[test]
+    fn held_back_renderings_time_out() {
+        let (vm, _) = fixtures::blueprint("Stalled");
+        let mut vm = vm.borrow_mut();
+        vm.sessions.insert(0, Session::new());
+        {
+            let session = vm.sessions.get_mut(&0).unwrap();
+            session.frame_state = FrameState::Sent;
+            session.pending = true;
+        }
+        vm.flush_expired();
+        assert!(vm.sessions[&0].pending);
+        assert!(vm.flush_timeout().unwrap() > time::Duration::from_secs(0));
+
+        vm.sessions.get_mut(&0).unwrap().last_update -= frame_timeout();
+        assert_eq!(vm.flush_timeout(), Some(time::Duration::from_secs(0)));
+        vm.flush_expired();
+        assert!(!vm.sessions[&0].pending);
+        assert!(!vm.sessions[&0].layers.is_empty());
+    }
+
+    #[test]
+    fn add_and_remove_blueprints() {
+        let vm = Vm::new_headless();
+        let first = vm.borrow_mut().add_blueprint("First".to_string());
+        let second = vm.borrow_mut().add_blueprint("Second".to_string());
+        vm.borrow_mut().activate(&second);
+        assert!(vm.borrow_mut().remove_blueprint(&second).is_ok());
+        assert!(vm.borrow_mut().remove_blueprint(&first).is_err());
+        assert!(Arc::ptr_eq(&vm.borrow().active_blueprint.upgrade().unwrap(), &first));
+
+        let third = vm.borrow_mut().add_blueprint("Third".to_string());
+        vm.borrow_mut().activate(&third);
+        let saved = serde_json::to_value(vm.borrow().deref()).unwrap();
+        let reloaded = Vm::new_headless();
+        Vm::load_value(&reloaded, saved).unwrap();
+        let reloaded = reloaded.borrow();
+        let names: Vec<String> = reloaded
+            .blueprints
+            .iter()
+            .map(|blueprint| blueprint.borrow().name.clone())
+            .collect();
+        assert_eq!(names, vec!["First".to_string(), "Third".to_string()]);
+        assert_eq!(reloaded.active_blueprint.upgrade().unwrap().borrow().name, "Third");
+    }
+
+    #[test]
+    fn process_pipes_input_to_output() {
+        let (vm, blueprint) = fixtures::blueprint("Pipeline");
+        let machine = blueprint.borrow().machines[0].clone();
+        let process = fixtures::frame(&blueprint, "Process", true);
+        let command = fixtures::text(&blueprint, "sort");
+        let input = fixtures::text(&blueprint, "b\na\n");
+        let output = fixtures::text(&blueprint, "");
+        link(&blueprint, param(&process, 0), LinkTerminator::Frame(command), 0);
+        link(&blueprint, param(&process, 2), LinkTerminator::Frame(input), 0);
+        link(&blueprint, param(&process, 3), LinkTerminator::Frame(output.clone()), 0);
+
+        let object = machine.borrow().get_object(&process);
+        let mut vm = vm.borrow_mut();
+        vm.process_task(Arc::downgrade(&object));
+        let output = machine.borrow().get_object(&output);
+        vm.run_until(|_| output.borrow().data.downcast_ref::<String>().unwrap() == "a\nb\n");
+    }
+
+    #[test]
+    fn arguments_follow_link_order() {
+        let (vm, blueprint) = fixtures::blueprint("Arguments");
+        let machine = blueprint.borrow().machines[0].clone();
+        let process = fixtures::frame(&blueprint, "Process", true);
+        let param = FrameParam {
+            frame: process.clone(),
+            param_index: 1,
+        };
+        for &(text, order) in [("b", 1), ("c", 2), ("a", 0)].iter() {
+            let frame = fixtures::text(&blueprint, text);
+            let start = LinkTerminator::FrameParam(param.clone());
+            link(&blueprint, start, LinkTerminator::Frame(frame), order);
+        }
+        let object = machine.borrow().get_object(&process);
+        let arguments = |vm: &Vm| -> Vec<String> {
+            vm.collect_args(&object)[1]
+                .iter()
+                .map(|arg| arg.borrow().data.downcast_ref::<String>().unwrap().clone())
+                .collect()
+        };
+        assert_eq!(arguments(&vm.borrow()), vec!["a", "b", "c"]);
+        assert_eq!(blueprint.borrow().next_order(&param), 3);
+
+        let last = blueprint.borrow().param_links(&param)[2].clone();
+        let action = Box::new(ReorderLinkAction {
+            link: Arc::downgrade(&last),
+            later: false,
+        });
+        action.start(&mut vm.borrow_mut(), DisplayPoint::new(0., 0.), WorldPoint::new(0., 0.));
+        assert_eq!(arguments(&vm.borrow()), vec!["a", "c", "b"]);
+        blueprint.borrow_mut().undo(0);
+        assert_eq!(arguments(&vm.borrow()), vec!["a", "b", "c"]);
+    }
 }